@@ -0,0 +1,170 @@
+/*
+    The ALU and barrel shifter, with the status lines of the principles of
+    operation. Subtraction adds the complement of the second operand with
+    a carry in of 1, so Carry-in is 1 for sub and 0 for every other
+    function, and Carry-out of a subtraction is 1 when no borrow occurred.
+    Shifts carry out the last bit shifted out. Overflow is signed overflow
+    of an addition or subtraction; the other functions clear it, and
+    clear Carry-out except for shifts.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AluFunction {
+    Add,
+    Sub,
+    And,
+    Or,
+    Nor,
+    SLL,
+    SRL,
+    SRA,
+    LoadLower, // sign-extended low byte of the second operand
+    LoadUpper, // low byte of the second operand over the first's low byte
+}
+
+pub const FUNCTIONS: [AluFunction; 10] = [
+    AluFunction::Add,
+    AluFunction::Sub,
+    AluFunction::And,
+    AluFunction::Or,
+    AluFunction::Nor,
+    AluFunction::SLL,
+    AluFunction::SRL,
+    AluFunction::SRA,
+    AluFunction::LoadLower,
+    AluFunction::LoadUpper,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Flags {
+    pub zero: bool,
+    pub negative: bool,
+    pub carry_out: bool,
+    pub carry_in: bool,
+    pub overflow: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AluOutput {
+    pub result: u16,
+    pub flags: Flags,
+}
+
+impl Flags {
+    /*
+        The status lines as bits, Zero highest: Z N Cout Cin V.
+    */
+    pub fn bits(&self) -> u8 {
+        (self.zero as u8) << 4 | (self.negative as u8) << 3 | (self.carry_out as u8) << 2
+            | (self.carry_in as u8) << 1 | self.overflow as u8
+    }
+}
+
+impl core::fmt::Display for Flags {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "Z={} N={} Cout={} Cin={} V={}",
+            self.zero as u8, self.negative as u8, self.carry_out as u8, self.carry_in as u8, self.overflow as u8)
+    }
+}
+
+/*
+    The function the ALU performs for an instruction, if it has one; r-type
+    instructions an ISA description adds may have none.
+*/
+pub fn alu_function(mnemonic: &str) -> Option<AluFunction> {
+    let function = match mnemonic {
+        "add" | "addi" | "lw" | "sw" | "jmp" | "jal" | "jmpi" | "jali" => AluFunction::Add,
+        "sub" | "subi" | "beq" | "bne" => AluFunction::Sub,
+        "and" => AluFunction::And,
+        "or" => AluFunction::Or,
+        "nor" => AluFunction::Nor,
+        "sll" => AluFunction::SLL,
+        "srl" => AluFunction::SRL,
+        "sra" => AluFunction::SRA,
+        "lli" => AluFunction::LoadLower,
+        "lui" => AluFunction::LoadUpper,
+        _ => return None,
+    };
+    Some(function)
+}
+
+pub fn alu(function: AluFunction, a: u16, b: u16) -> AluOutput {
+    let mut flags = Flags::default();
+    let result = match function {
+        AluFunction::Add | AluFunction::Sub => {
+            let (b, carry_in) = if function == AluFunction::Sub { (!b, 1) } else { (b, 0) };
+            let sum = a as u32 + b as u32 + carry_in;
+            let result = sum as u16;
+            flags.carry_in = carry_in == 1;
+            flags.carry_out = sum > 0xFFFF;
+            // operands of one sign giving a result of the other
+            flags.overflow = (a ^ result) & (b ^ result) & 0x8000 != 0;
+            result
+        },
+        AluFunction::And => a & b,
+        AluFunction::Or => a | b,
+        AluFunction::Nor => !(a | b),
+        // amounts of 16 or more shift every bit out
+        AluFunction::SLL => {
+            flags.carry_out = (1..=16).contains(&b) && a >> (16 - b) & 1 == 1;
+            if b < 16 { a << b } else { 0 }
+        },
+        AluFunction::SRL => {
+            flags.carry_out = (1..=16).contains(&b) && a >> (b - 1) & 1 == 1;
+            if b < 16 { a >> b } else { 0 }
+        },
+        AluFunction::SRA => {
+            flags.carry_out = b >= 1 && (a as i16 >> (b - 1).min(15)) & 1 == 1;
+            ((a as i16) >> b.min(15)) as u16
+        },
+        AluFunction::LoadLower => b as u8 as i8 as u16,
+        AluFunction::LoadUpper => (b << 8) | (a & 0xFF),
+    };
+    flags.zero = result == 0;
+    flags.negative = result & 0x8000 != 0;
+    AluOutput { result, flags }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::alu::*;
+
+    fn flags(zero: bool, negative: bool, carry_out: bool, carry_in: bool, overflow: bool) -> Flags {
+        Flags { zero, negative, carry_out, carry_in, overflow }
+    }
+
+    #[test]
+    fn alu_function_of_instructions() {
+        assert_eq!(alu_function("bne"), Some(AluFunction::Sub));
+        assert_eq!(alu_function("lui"), Some(AluFunction::LoadUpper));
+        assert_eq!(alu_function("xor"), None);
+    }
+
+    #[test]
+    fn add_and_sub_flags() {
+        assert_eq!(alu(AluFunction::Add, 0x7FFF, 1), AluOutput { result: 0x8000, flags: flags(false, true, false, false, true) });
+        assert_eq!(alu(AluFunction::Add, 0xFFFF, 1), AluOutput { result: 0, flags: flags(true, false, true, false, false) });
+        assert_eq!(alu(AluFunction::Sub, 5, 5), AluOutput { result: 0, flags: flags(true, false, true, true, false) });
+        assert_eq!(alu(AluFunction::Sub, 3, 5), AluOutput { result: 0xFFFE, flags: flags(false, true, false, true, false) });
+        assert_eq!(alu(AluFunction::Sub, 0x8000, 1), AluOutput { result: 0x7FFF, flags: flags(false, false, true, true, true) });
+    }
+
+    #[test]
+    fn shifter_carries_out_the_last_bit() {
+        assert_eq!(alu(AluFunction::SLL, 0x4001, 2), AluOutput { result: 4, flags: flags(false, false, true, false, false) });
+        assert_eq!(alu(AluFunction::SRL, 0x8002, 2), AluOutput { result: 0x2000, flags: flags(false, false, true, false, false) });
+        assert_eq!(alu(AluFunction::SRA, 0x8000, 20).result, 0xFFFF);
+        assert_eq!(alu(AluFunction::SRL, 0x8000, 16), AluOutput { result: 0, flags: flags(true, false, true, false, false) });
+        assert!(!alu(AluFunction::SLL, 0xFFFF, 0).flags.carry_out);
+    }
+
+    #[test]
+    fn add_matches_wide_arithmetic() {
+        for (a, b) in [(0u16, 0u16), (1, 0xFFFF), (0x8000, 0x8000), (0x1234, 0x7654), (0xFFFF, 0xFFFF)].iter() {
+            let sum = *a as i16 as i32 + *b as i16 as i32;
+            let output = alu(AluFunction::Add, *a, *b);
+            assert_eq!(output.flags.overflow, sum < i16::MIN as i32 || sum > i16::MAX as i32);
+            assert_eq!(output.flags.carry_out, *a as u32 + *b as u32 > 0xFFFF);
+        }
+    }
+}
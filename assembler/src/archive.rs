@@ -0,0 +1,110 @@
+/*
+    Static archives of objects, built by `archive` and searched by `link`.
+    An archive is plain text: a header followed by each member's name and
+    the member's object text:
+
+        archive 1
+        member print.o
+        object 1
+        section .text 4
+        ...
+        member math.o
+        object 1
+        ...
+
+    Linking pulls in only the members that define a global symbol some
+    object still needs.
+*/
+
+use object::Object;
+
+const VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Archive {
+    pub members: Vec<(String, Object)>,
+}
+
+impl Archive {
+    pub fn parse(text: &str) -> Archive {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+        match lines.next().map(|line| line.split_whitespace().collect::<Vec<_>>()) {
+            Some(ref parts) if parts.len() == 2 && parts[0] == "archive" && parts[1] == VERSION.to_string() => (),
+            _ => panic!("Not a version {} archive", VERSION),
+        }
+        let mut members: Vec<(String, String)> = Vec::new();
+        for line in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts[0] == "member" {
+                if parts.len() != 2 {
+                    panic!("Expected a member name: {}", line);
+                }
+                members.push((parts[1].to_string(), String::new()));
+                continue;
+            }
+            match members.last_mut() {
+                Some((_, text)) => {
+                    text.push_str(line);
+                    text.push('\n');
+                },
+                None => panic!("Expected a member before: {}", line),
+            }
+        }
+        Archive {
+            members: members
+                .into_iter()
+                .map(|(name, text)| {
+                    let object = Object::parse(&text);
+                    (name, object)
+                })
+                .collect(),
+        }
+    }
+
+    /*
+        Whether text holds an archive rather than a single object.
+    */
+    pub fn is_archive(text: &str) -> bool {
+        text.trim_start().starts_with("archive ")
+    }
+}
+
+impl core::fmt::Display for Archive {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "archive {}", VERSION)?;
+        for (name, object) in self.members.iter() {
+            writeln!(f, "member {}", name)?;
+            write!(f, "{}", object)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{archive::Archive, assembler::assemble, assembler::Options, isa::Isa, object::Object};
+
+    fn object(text: &str) -> Object {
+        let options = Options { object: true, ..Options::default() };
+        Object::from_program(&assemble(text, &options), &Isa::default())
+    }
+
+    #[test]
+    fn parse_archive_round_trip() {
+        let archive = Archive {
+            members: vec![
+                (String::from("print.o"), object(".globl print\nprint:\njmpi 0($ra)\n")),
+                (String::from("math.o"), object(".globl square\n.extern print\nsquare:\njal print\n")),
+            ],
+        };
+        let written = archive.to_string();
+        assert!(written.starts_with("archive 1\nmember print.o\nobject 1\n"));
+        assert_eq!(Archive::parse(&written), archive);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a version 1 archive")]
+    fn parse_rejects_objects() {
+        Archive::parse("object 1\n");
+    }
+}
@@ -8,71 +8,338 @@ use ir::RType;
 use ir::JType;
 use ir::IType;
 use ir::Pseudo;
+use ir::Half;
 use ir::IRInstruction;
+use isa::Format;
+use isa::Isa;
+use isa::known_mnemonics;
+use memory::MemoryLayout;
+use object;
+use output;
+use output::Image;
+use output::ImageWriter;
+use peephole;
 
 const INSTR_SIZE: u16 = 1;
-static MIF_HEADER: &'static str = 
-"DEPTH = 16384;                -- The size of memory in words
-WIDTH = 16;                   -- The size of data in bits
-ADDRESS_RADIX = HEX;          -- The radix for address values
-DATA_RADIX = BIN;             -- The radix for data values
-CONTENT                       -- start of (address : data pairs)
-BEGIN\n";
+const LI_SIZE: u16 = 2; // lli followed by lui
+const FAR_JUMP_SIZE: u16 = LI_SIZE + INSTR_SIZE; // full li $at followed by jmpi/jali
+const JUMP_ADDR_MASK: u16 = 0xFFF; // word address bits held by a j-type instruction
+const IMM_BITS: u32 = 6; // immediate of i-type instructions with two registers
+const WIDE_IMM_BITS: u32 = 9; // immediate of i-type instructions with one register
 
 
 
-pub fn parse(text: String, output_file: String) -> std::io::Result<()> {
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub relax: bool, // rewrite control transfers whose targets are out of reach
+    pub optimize: bool, // run the peephole pass before layout
+    pub object: bool, // emit a relocatable object instead of an image
+    pub layout: MemoryLayout, // where the sections of an image go
+    pub write: output::WriteOptions,
+    pub file: String, // name of the source being assembled, for messages
+}
+
+/*
+    An assembled program: its instructions by address, its symbol table and
+    where each of its sections was placed. Instructions that refer to
+    labels are only left unresolved in relocatable programs.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub instrs: Vec<(u16, IRInstruction)>,
+    pub symbols: HashMap<String, u16>,
+    pub sections: Vec<PlacedSection>,
+    pub globals: Vec<String>, // symbols exported with .globl
+    pub externs: Vec<String>, // symbols declared with .extern
+}
+
+/*
+    A section of a program: where it starts, how many words it spans and
+    the labels defined in it.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlacedSection {
+    pub name: String,
+    pub base: u16,
+    pub size: u16,
+    pub labels: Vec<String>,
+}
+
+impl PlacedSection {
+    pub fn contains(&self, addr: u16) -> bool {
+        addr >= self.base && (addr as u32) < self.base as u32 + self.size as u32
+    }
+}
+
+pub fn parse(text: String, output_file: String, isa: &Isa, options: &Options, writer: &dyn ImageWriter) -> std::io::Result<()> {
     let mut out_file = File::create(&output_file)?;
-    let ir1 = to_ir1(text);
-    let parsed = to_mif(to_ir2(ir1.0, ir1.1));
-    out_file.write_all(parsed.as_bytes())?;
+    let program = assemble(&text, options);
+    let parsed = if options.object {
+        object::Object::from_program(&program, isa).to_string().into_bytes()
+    } else {
+        writer.write(&image(&program, isa), &options.write)
+    };
+    out_file.write_all(&parsed)?;
     Ok(())
 }
 
 /*
-    First pass of the assembler. Parses into IR and creates a symbol 
-    table (map of symbol to address).
+    How far a control transfer to a label can reach, from a plain branch
+    offset, through a j-type jump within the current region, to an
+    absolute jump through $at.
+*/
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Reach {
+    Near,
+    Region,
+    Far,
+}
+
+/*
+    Runs the first two passes until the layout settles. When relaxing,
+    branches and jumps whose targets are out of reach are widened into
+    longer sequences, which may push other targets out of reach, so
+    passes repeat until every transfer reaches its target.
+*/
+pub fn assemble(text: &str, options: &Options) -> Program {
+    let mut lines: Vec<IRInstruction> = text.lines().map(parse_line).collect();
+    if options.optimize {
+        // before layout, so the passes below recompute every address for the smaller code
+        lines = peephole::optimize(lines);
+    }
+    let mut globals: Vec<String> = Vec::new();
+    let mut externs: Vec<String> = Vec::new();
+    for line in lines.iter() {
+        match line {
+            IRInstruction::Directive(Directive::Globl(name)) if !globals.contains(name) => globals.push(name.clone()),
+            IRInstruction::Directive(Directive::Extern(name)) if !externs.contains(name) => externs.push(name.clone()),
+            _ => (),
+        }
+    }
+    let mut reach: HashMap<usize, Reach> = HashMap::new();
+    loop {
+        let (instrs, symbols, sections) = to_ir1(&lines, &reach, options);
+        if let Some(name) = globals.iter().find(|name| !symbols.contains_key(*name)) {
+            panic!("{} is declared .globl{} but never defined", name, in_file(&options.file));
+        }
+        for (_, instr) in instrs.iter() {
+            match referenced_label(instr) {
+                // only objects may leave references for the linker
+                Some(label) if !symbols.contains_key(label) && (!options.object || !externs.contains(label)) =>
+                    panic!("{}", undefined_label_message(label, &options.file, &symbols)),
+                _ => (),
+            }
+        }
+        let widened = if options.relax {
+            find_out_of_reach(&instrs, &symbols, &sections, &reach, options.object)
+        } else {
+            vec![]
+        };
+        if widened.is_empty() {
+            check_numeric_branches(&instrs, &reach);
+            let mut instrs = to_ir2(instrs, &symbols, &sections, &reach, options.object);
+            // sections interleave in the source but not in memory
+            instrs.sort_by_key(|(addr, _)| *addr);
+            return Program {
+                instrs,
+                symbols,
+                sections,
+                globals,
+                externs,
+            };
+        }
+        reach.extend(widened);
+    }
+}
+
+/*
+    First pass of the assembler. Assigns addresses to the parsed lines
+    and creates a symbol table (map of symbol to address). Branches and
+    jumps to labels are laid out with the reach recorded for their line.
+    Every section counts from zero, and once their sizes are known they
+    are placed by the memory layout, or one after the other in an object.
 */
-fn to_ir1(text: String) -> (Vec<(u16, IRInstruction)>, HashMap<String, u16>) {
-    let mut addr_counter: u16 = 0;
-    let mut symbol_table: HashMap<String, u16> = HashMap::new();
-    let mut instrs: Vec<(u16, IRInstruction)> = Vec::new();
-    for line in text.lines() {
-        let instr = parse_line(line);
-        instrs.push((addr_counter, instr.clone()));
-        match instr { // todo: handle pseudo-instr
+fn to_ir1(lines: &[IRInstruction], reach: &HashMap<usize, Reach>, options: &Options) -> (Vec<(u16, IRInstruction)>, HashMap<String, u16>, Vec<PlacedSection>) {
+    let mut sections: Vec<PlacedSection> = vec![PlacedSection {
+        name: String::from(".text"),
+        base: 0,
+        size: 0,
+        labels: vec![],
+    }];
+    let mut counters: Vec<u16> = vec![0];
+    let mut current: usize = 0;
+    let mut offsets: Vec<(usize, u16)> = Vec::new();
+    let mut label_offsets: HashMap<String, (usize, u16)> = HashMap::new();
+    for (idx, instr) in lines.iter().cloned().enumerate() {
+        if let IRInstruction::Directive(Directive::Section(name)) = &instr {
+            current = match sections.iter().position(|section| &section.name == name) {
+                Some(i) => i,
+                None => {
+                    sections.push(PlacedSection { name: name.clone(), base: 0, size: 0, labels: vec![] });
+                    counters.push(0);
+                    sections.len() - 1
+                },
+            };
+        }
+        if sections[current].name == ".bss" && takes_data(&instr) {
+            panic!("{} cannot go in .bss, which holds no data; reserve space with .space", instr);
+        }
+        let addr_counter = &mut counters[current];
+        offsets.push((current, *addr_counter));
+        match instr {
             IRInstruction::Label(name) => {
-                symbol_table.insert(name.to_string(), addr_counter.clone());
-                addr_counter += INSTR_SIZE;
+                // labels name the address of whatever follows them
+                sections[current].labels.push(name.clone());
+                label_offsets.insert(name, (current, *addr_counter));
             },
+            IRInstruction::BlankLine => (),
             IRInstruction::Directive(dir) => {
                 match dir {
-                    Directive::Asciiz(s) => addr_counter += s.len() as u16,
-                    Directive::Org(new_cnt) => addr_counter = new_cnt,
-                    Directive::Space(space) => addr_counter += space,
-                    Directive::Byte(_) => addr_counter += INSTR_SIZE,
-                    Directive::Word(_) => addr_counter += INSTR_SIZE,
+                    Directive::Asciiz(s) => *addr_counter += s.len() as u16,
+                    Directive::Org(new_cnt) => *addr_counter = new_cnt, // relative to the section
+                    Directive::Space(space) => *addr_counter += space,
+                    Directive::Byte(_) => *addr_counter += INSTR_SIZE,
+                    Directive::Word(_) => *addr_counter += INSTR_SIZE,
+                    Directive::Globl(_) | Directive::Extern(_) | Directive::Section(_) => (),
+                }
+            }
+            IRInstruction::JTypeLabel(_, _) | IRInstruction::ITypeLabel(_, _, _, _) =>
+                *addr_counter += transfer_size(&instr, reach_of(reach, idx, &instr)),
+            IRInstruction::Pseudo(Pseudo::LI(reg, val)) => *addr_counter += expand_li(0, &reg, val).len() as u16,
+            _ => *addr_counter += INSTR_SIZE,
+        }
+        sections[current].size = sections[current].size.max(*addr_counter);
+    }
+
+    let bases: Vec<u16> = if options.object {
+        let mut next: u32 = 0;
+        sections
+            .iter()
+            .map(|section| {
+                let base = next;
+                next += section.size as u32;
+                if next > 1 << 16 {
+                    panic!("Sections need {} words, more than the address space holds", next);
                 }
+                base as u16
+            })
+            .collect()
+    } else {
+        let sizes: Vec<(String, u16)> = sections.iter().map(|section| (section.name.clone(), section.size)).collect();
+        options.layout.place(&sizes)
+    };
+    for (section, base) in sections.iter_mut().zip(bases.iter()) {
+        section.base = *base;
+    }
+    let instrs = lines
+        .iter()
+        .zip(offsets.iter())
+        .map(|(instr, (section, offset))| (bases[*section] + offset, instr.clone()))
+        .collect();
+    let symbol_table = label_offsets
+        .into_iter()
+        .map(|(name, (section, offset))| (name, bases[section] + offset))
+        .collect();
+    (instrs, symbol_table, sections)
+}
+
+/*
+    Whether a line puts words in memory rather than only reserving or
+    naming them.
+*/
+fn takes_data(instr: &IRInstruction) -> bool {
+    match instr {
+        IRInstruction::Label(_) | IRInstruction::BlankLine => false,
+        IRInstruction::Directive(dir) => match dir {
+            Directive::Org(_) | Directive::Space(_) | Directive::Globl(_) | Directive::Extern(_) | Directive::Section(_) => false,
+            _ => true,
+        },
+        _ => true,
+    }
+}
+
+/*
+    Whether a transfer at addr and the label it refers to lie in the same
+    section, so that the distance between them is known before linking.
+*/
+fn same_section(sections: &[PlacedSection], addr: u16, label: &str) -> bool {
+    sections
+        .iter()
+        .any(|section| section.contains(addr) && section.labels.iter().any(|l| l == label))
+}
+
+/*
+    Returns the lines of branches and jumps whose targets are out of
+    reach, along with the next wider reach to try for each. Relocatable
+    programs only know the distances between their own labels, so only
+    their branches to labels in the same section are widened, and never
+    past a jmp.
+*/
+fn find_out_of_reach(instrs: &[(u16, IRInstruction)], symbols: &HashMap<String, u16>, sections: &[PlacedSection], reach: &HashMap<usize, Reach>, relocatable: bool) -> Vec<(usize, Reach)> {
+    instrs
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, (addr, instr))| {
+            let label = match instr {
+                IRInstruction::ITypeLabel(_, _, _, label) => label,
+                IRInstruction::JTypeLabel(_, label) if !relocatable => label,
+                _ => return None,
+            };
+            let current = reach_of(reach, idx, instr);
+            let target = match symbols.get(label) {
+                Some(target) if !relocatable || same_section(sections, *addr, label) => *target,
+                _ => return None, // placed by the linker
+            };
+            if reaches(instr, *addr, target, current) {
+                return None;
             }
-            _ => addr_counter += INSTR_SIZE,
+            match current {
+                Reach::Near => Some((idx, Reach::Region)),
+                Reach::Region if !relocatable => Some((idx, Reach::Far)),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/*
+    Numeric branch offsets count the words as written, so relaxation must
+    not have grown a transfer that one of them branches over.
+*/
+fn check_numeric_branches(instrs: &[(u16, IRInstruction)], reach: &HashMap<usize, Reach>) {
+    let mut grown: Vec<usize> = reach.keys().cloned().collect();
+    grown.sort();
+    for (addr, instr) in instrs.iter() {
+        let (itype, offset) = match instr {
+            IRInstruction::IType(itype, _, _, offset) if *itype == IType::BEQ || *itype == IType::BNE => (itype, *offset as i32),
+            _ => continue,
+        };
+        let next = (*addr + INSTR_SIZE) as i32;
+        let (first, last) = if offset >= 0 { (next, next + offset) } else { (next + offset, next) };
+        if let Some(idx) = grown.iter().find(|idx| (first..last).contains(&(instrs[**idx].0 as i32))) {
+            panic!("The {} at {:#X} branches {} words over the transfer at {:#X}, which --relax made longer; branch to a label instead",
+                itype, addr, offset, instrs[*idx].0);
         }
     }
-    (instrs, symbol_table)
 }
 
 /*
     Second pass of the assembler. Replaces symbols with actual address
-    and replaces pseudo-instrs. with real instrs.
+    and replaces pseudo-instrs. with real instrs. Relocatable programs
+    keep references to labels whose address depends on where the linker
+    places them.
 */
-fn to_ir2(instrs: Vec<(u16, IRInstruction)>, symbols: HashMap<String, u16>) -> Vec<(u16, IRInstruction)> {
+fn to_ir2(instrs: Vec<(u16, IRInstruction)>, symbols: &HashMap<String, u16>, sections: &[PlacedSection], reach: &HashMap<usize, Reach>, relocatable: bool) -> Vec<(u16, IRInstruction)> {
     instrs
         .iter()
-        .filter(|(_, instr)| {
+        .enumerate()
+        .filter(|(_, (_, instr))| {
             match instr {
                 IRInstruction::Directive(dir) => {
                     match dir {
                         Directive::Space(_) => false,
                         Directive::Org(_) => false,
+                        Directive::Globl(_) | Directive::Extern(_) | Directive::Section(_) => false,
                         _ => true,
                     }
                 },
@@ -80,19 +347,54 @@ fn to_ir2(instrs: Vec<(u16, IRInstruction)>, symbols: HashMap<String, u16>) -> V
                 _ => true
             }
         })
-        .fold(vec![], |mut acc, (addr, instr)| {
+        .fold(vec![], |mut acc, (idx, (addr, instr))| {
             match instr {
+                IRInstruction::JTypeLabel(_, _) | IRInstruction::ITypeWideLabel(_, _, _, _) if relocatable => {
+                    acc.push((*addr, instr.clone()));
+                },
+                IRInstruction::ITypeLabel(_, _, _, label) if relocatable && !same_section(sections, *addr, label) => {
+                    acc.push((*addr, instr.clone()));
+                },
                 IRInstruction::JTypeLabel(j_type, label) => {
-                    acc.push((*addr, IRInstruction::JTypeAddr(j_type.clone(), *symbols.get(label).unwrap()+INSTR_SIZE)));
+                    let target = lookup_symbol(symbols, label);
+                    match reach_of(reach, idx, instr) {
+                        Reach::Far => acc.extend(expand_far_jump(*addr, j_type, target)),
+                        _ if in_jump_region(*addr, target) =>
+                            acc.push((*addr, IRInstruction::JTypeAddr(j_type.clone(), target & JUMP_ADDR_MASK))),
+                        _ => panic!("Target {} ({:#X}) of the {} at {:#X} is outside its {}K region; assemble with --relax to use a far jump",
+                            label, target, j_type, addr, (JUMP_ADDR_MASK as u32 + 1) * 2 / 1024),
+                    }
+                },
+                IRInstruction::ITypeLabel(i_type, reg1, reg2, label) => {
+                    let target = lookup_symbol(symbols, label);
+                    match reach_of(reach, idx, instr) {
+                        Reach::Near if reaches(instr, *addr, target, Reach::Near) =>
+                            acc.push((*addr, IRInstruction::IType(i_type.clone(), reg1.clone(), reg2.clone(), branch_offset(*addr, target) as i8))),
+                        Reach::Near => panic!("Target {} ({:#X}) of the {} at {:#X} is {} words away, beyond the branch range; assemble with --relax to branch over a jump",
+                            label, target, i_type, addr, branch_offset(*addr, target)),
+                        Reach::Region => {
+                            // skip over a jump unless the opposite condition holds
+                            acc.push((*addr, IRInstruction::IType(invert_branch(i_type), reg1.clone(), reg2.clone(), 1)));
+                            let jump = if relocatable {
+                                IRInstruction::JTypeLabel(JType::Jmp, label.clone())
+                            } else {
+                                IRInstruction::JTypeAddr(JType::Jmp, target & JUMP_ADDR_MASK)
+                            };
+                            acc.push((*addr + INSTR_SIZE, jump));
+                        },
+                        Reach::Far => {
+                            acc.push((*addr, IRInstruction::IType(invert_branch(i_type), reg1.clone(), reg2.clone(), FAR_JUMP_SIZE as i8)));
+                            acc.extend(expand_far_jump(*addr + INSTR_SIZE, &JType::Jmp, target));
+                        },
+                    }
+                },
+                IRInstruction::ITypeWideLabel(i_type, reg, half, label) => {
+                    let target = lookup_symbol(symbols, label);
+                    acc.push((*addr, IRInstruction::ITypeWide(i_type.clone(), reg.clone(), address_half(target, half))));
                 },
                 IRInstruction::Pseudo(pseudo) => {
                     match pseudo {
-                        Pseudo::LI(reg, val) => {
-                            let lower = (val & 0xFF) as i8;
-                            let upper = (val >> 8) as i8;
-                            acc.push((*addr, IRInstruction::IType(IType::LLI, reg.clone(), Register::None, lower)));
-                            acc.push((*addr + INSTR_SIZE, IRInstruction::IType(IType::LUI, reg.clone(), Register::None, upper)));
-                        }
+                        Pseudo::LI(reg, val) => acc.extend(expand_li(*addr, reg, *val)),
                     }
                 },
                 _ => {
@@ -104,20 +406,209 @@ fn to_ir2(instrs: Vec<(u16, IRInstruction)>, symbols: HashMap<String, u16>) -> V
 }
 
 /*
-    Final pass of the assembler. Adds MIF preamble and translates instructions
-    to binary.
+    The byte of a label's byte address selected by %hi or %lo, which
+    lui and lli load without any adjustment. lli sign-extends, so a full
+    address takes lli %lo first and lui %hi after it.
 */
-fn to_mif(instrs: Vec<(u16, IRInstruction)>) -> String {
-    MIF_HEADER.to_owned() +
-    &instrs
-        .iter()
-        .map(|(addr, instr)| instr_to_mif(addr, instr))
-        .collect::<Vec<_>>()
-        .join("\n") +
-    "\nEND;\n"
+pub fn address_half(target: u16, half: &Half) -> i16 {
+    let byte_addr = target << 1;
+    match half {
+        Half::Hi => (byte_addr >> 8) as i16,
+        Half::Lo => (byte_addr & 0xFF) as i16,
+    }
+}
+
+fn reach_of(reach: &HashMap<usize, Reach>, idx: usize, instr: &IRInstruction) -> Reach {
+    match reach.get(&idx) {
+        Some(r) => *r,
+        None => match instr {
+            IRInstruction::JTypeLabel(_, _) => Reach::Region,
+            _ => Reach::Near,
+        },
+    }
+}
+
+fn transfer_size(instr: &IRInstruction, reach: Reach) -> u16 {
+    // relaxed branches keep an inverted branch in front of their jump
+    let branch = match instr {
+        IRInstruction::ITypeLabel(_, _, _, _) => INSTR_SIZE,
+        _ => 0,
+    };
+    match reach {
+        Reach::Near => INSTR_SIZE,
+        Reach::Region => branch + INSTR_SIZE,
+        Reach::Far => branch + FAR_JUMP_SIZE,
+    }
+}
+
+/*
+    Whether a transfer at addr laid out with the given reach can get to
+    target.
+*/
+fn reaches(instr: &IRInstruction, addr: u16, target: u16, reach: Reach) -> bool {
+    // a relaxed branch leads with the inverted branch, so its jump sits one word later
+    let jump_addr = match instr {
+        IRInstruction::ITypeLabel(_, _, _, _) => addr + INSTR_SIZE,
+        _ => addr,
+    };
+    match reach {
+        Reach::Near => {
+            let offset = branch_offset(addr, target);
+            offset >= -(1 << (IMM_BITS - 1)) && offset < 1 << (IMM_BITS - 1)
+        },
+        Reach::Region => in_jump_region(jump_addr, target),
+        Reach::Far => true,
+    }
+}
+
+/*
+    Branches are relative to the incremented PC.
+*/
+fn branch_offset(addr: u16, target: u16) -> i32 {
+    target as i32 - (addr + INSTR_SIZE) as i32
+}
+
+fn invert_branch(itype: &IType) -> IType {
+    match itype {
+        IType::BEQ => IType::BNE,
+        IType::BNE => IType::BEQ,
+        _ => panic!("Cannot invert {}, it is not a branch", itype),
+    }
+}
+
+/*
+    Jumps through $at: li $at, target; jmpi/jali 0($at)
+*/
+fn expand_far_jump(addr: u16, jtype: &JType, target: u16) -> Vec<(u16, IRInstruction)> {
+    let jump = match jtype {
+        JType::Jmp => IType::JmpI,
+        JType::Jal => IType::JalI,
+    };
+    // always the full li, so the size of a far jump doesn't depend on its target
+    let mut instrs = expand_li_full(addr, &Register::At, (target << 1) as i16);
+    instrs.push((addr + LI_SIZE, IRInstruction::ITypeWide(jump, Register::At, 0)));
+    instrs
+}
+
+/*
+    Loads a 16-bit constant with as few instructions as the ISA allows.
+    The principles of operation still describe a single LI; the LLI and
+    LUI this targets work as the hardware built this semester does. LLI
+    sign-extends its byte into the whole register, so it loads any value
+    from -128 to 127 on its own.
+*/
+pub fn expand_li(addr: u16, reg: &Register, val: i16) -> Vec<(u16, IRInstruction)> {
+    if val as i8 as i16 == val {
+        vec![(addr, IRInstruction::ITypeWide(IType::LLI, reg.clone(), val))]
+    } else {
+        expand_li_full(addr, reg, val)
+    }
+}
+
+/*
+    LLI sets the register to the sign-extended low byte, then LUI
+    replaces the upper byte and keeps the low one, which loads any
+    16-bit value.
+*/
+fn expand_li_full(addr: u16, reg: &Register, val: i16) -> Vec<(u16, IRInstruction)> {
+    let lower = val as i8 as i16;
+    let upper = (val as u16 >> 8) as i16;
+    vec![
+        (addr, IRInstruction::ITypeWide(IType::LLI, reg.clone(), lower)),
+        (addr + INSTR_SIZE, IRInstruction::ITypeWide(IType::LUI, reg.clone(), upper)),
+    ]
+}
+
+/*
+    J-type instructions replace the low bits of the incremented PC, so they
+    can only reach targets in the same region as the instruction after them.
+    Addresses here are word addresses; the hardware's extra shift by one
+    turns them into byte addresses.
+*/
+fn in_jump_region(addr: u16, target: u16) -> bool {
+    (addr + INSTR_SIZE) & !JUMP_ADDR_MASK == target & !JUMP_ADDR_MASK
+}
+
+fn lookup_symbol(symbols: &HashMap<String, u16>, label: &str) -> u16 {
+    *symbols
+        .get(label)
+        .unwrap_or_else(|| panic!("{}", undefined_label_message(label, "", symbols)))
+}
+
+fn referenced_label(instr: &IRInstruction) -> Option<&String> {
+    match instr {
+        IRInstruction::JTypeLabel(_, label)
+        | IRInstruction::ITypeLabel(_, _, _, label)
+        | IRInstruction::ITypeWideLabel(_, _, _, label) => Some(label),
+        _ => None,
+    }
+}
+
+fn undefined_label_message(label: &str, file: &str, symbols: &HashMap<String, u16>) -> String {
+    let candidates = similar_names(label, symbols.keys());
+    if candidates.is_empty() {
+        format!("Undefined label {}{}", label, in_file(file))
+    } else {
+        format!("Undefined label {}{} (did you mean {}?)", label, in_file(file), candidates.join(", "))
+    }
+}
+
+// names the source in a message, when it came from a file
+fn in_file(file: &str) -> String {
+    if file.is_empty() {
+        String::new()
+    } else {
+        format!(" in {}", file)
+    }
+}
+
+/*
+    Names close enough to the given one to be likely typos of it, closest
+    first.
+*/
+pub fn similar_names<'a, I: Iterator<Item = &'a String>>(name: &str, candidates: I) -> Vec<String> {
+    let limit = (name.len() / 3).max(1);
+    let mut close: Vec<(usize, String)> = candidates
+        .map(|candidate| (edit_distance(&name.to_lowercase(), &candidate.to_lowercase()), candidate.clone()))
+        .filter(|(distance, _)| *distance <= limit)
+        .collect();
+    close.sort();
+    close.dedup();
+    close.into_iter().map(|(_, candidate)| candidate).collect()
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for j in 0..b.len() {
+            let substitute = diagonal + if ca == b[j] { 0 } else { 1 };
+            diagonal = row[j + 1];
+            row[j + 1] = substitute.min(row[j] + 1).min(row[j + 1] + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/*
+    Final pass of the assembler. Translates instructions to binary,
+    remembering the line each word came from.
+*/
+pub fn image(program: &Program, isa: &Isa) -> Image {
+    let mut words: Vec<(u16, u16)> = Vec::new();
+    let mut source: Vec<(u16, String)> = Vec::new();
+    for (addr, instr) in program.instrs.iter() {
+        for (i, word) in instr_to_words(instr, isa).into_iter().enumerate() {
+            words.push((*addr + i as u16, word));
+        }
+        source.push((*addr, instr.to_string()));
+    }
+    Image { words, source, symbols: output::by_address(&program.symbols) }
 }
 
-pub fn instr_to_mif(addr: &u16, instr: &IRInstruction) -> String {
+pub fn instr_to_mif(addr: &u16, instr: &IRInstruction, isa: &Isa) -> String {
     match instr {
         IRInstruction::Directive(dir) => {
                 match dir {
@@ -130,15 +621,25 @@ pub fn instr_to_mif(addr: &u16, instr: &IRInstruction) -> String {
                             .fold(String::new(), |a, b| a + " " + &b);
                         return format!("{:X} : {}; -- {}", addr, bytes, instr);
                     },
-                   _ => format!("{:X} : {:X}; -- {}", addr, instr_to_int(instr), instr), 
+                   _ => format!("{:X} : {:X}; -- {}", addr, instr_to_int(instr, isa), instr), 
                 }
         },
-        _ => format!("{:X} : {:X}; -- {}", addr, instr_to_int(instr), instr),
+        _ => format!("{:X} : {:X}; -- {}", addr, instr_to_int(instr, isa), instr),
     }
     
 }
 
-fn instr_to_int(instr: &IRInstruction) -> u16 {
+/*
+    The words an instruction or data directive occupies in memory.
+*/
+pub fn instr_to_words(instr: &IRInstruction, isa: &Isa) -> Vec<u16> {
+    match instr {
+        IRInstruction::Directive(Directive::Asciiz(s)) => s.as_bytes().iter().map(|b| *b as u16).collect(),
+        _ => vec![instr_to_int(instr, isa)],
+    }
+}
+
+fn instr_to_int(instr: &IRInstruction, isa: &Isa) -> u16 {
     match instr {
         IRInstruction::Directive(dir) => {
             return match dir {
@@ -148,70 +649,41 @@ fn instr_to_int(instr: &IRInstruction) -> u16 {
             }
         },
         IRInstruction::RType(rtype, reg1, reg2, reg3) => {
-            return (reg_to_int(reg1) << 9) 
-            + (reg_to_int(reg2) << 6) 
-            + (reg_to_int(reg3) << 3)
-            + rtype_to_int(rtype)
+            let encoding = isa.encoding(&rtype.to_string());
+            if encoding.format != Format::R {
+                panic!("Unrecognized instruction {} with three registers", rtype);
+            }
+            let layout = isa.layout(Format::R);
+            return layout.place("op", encoding.opcode)
+            | layout.place("r1", reg_to_int(reg1, isa))
+            | layout.place("r2", reg_to_int(reg2, isa))
+            | layout.place("r3", reg_to_int(reg3, isa))
+            | layout.place("funct", encoding.funct)
         },
         IRInstruction::JTypeAddr(jtype, addr) => {
-            return (jtype_to_int(jtype) << 12) + addr
+            let layout = isa.layout(Format::J);
+            return layout.place("op", isa.encoding(&jtype.to_string()).opcode)
+            | layout.place("address", *addr)
         },
         IRInstruction::IType(itype, reg1, reg2, immediate) => {
-            return (itype_to_int(itype) << 12)
-            + (reg_to_int(reg1) << 9)
-            + (reg_to_int(reg2) << 6)
-            + ((*immediate as u8) as u16)
+            let layout = isa.layout(Format::I);
+            return layout.place("op", isa.encoding(&itype.to_string()).opcode)
+            | layout.place("r1", reg_to_int(reg1, isa))
+            | layout.place("r2", reg_to_int(reg2, isa))
+            | layout.place("imm", *immediate as u16)
+        },
+        IRInstruction::ITypeWide(itype, reg, immediate) => {
+            let layout = isa.layout(Format::IWide);
+            return layout.place("op", isa.encoding(&itype.to_string()).opcode)
+            | layout.place("r1", reg_to_int(reg, isa))
+            | layout.place("imm", *immediate as u16)
         },
         _ => panic!("Unexpected instruction type"),
     } 
 }
 
-fn rtype_to_int(rtype: &RType) -> u16 {
-    match rtype {
-        RType::Add => 0,
-        RType::Sub => 1,
-        RType::And => 2,
-        RType::Or => 3,
-        RType::Nor => 4,
-        RType::SLL => 5,
-        RType::SRL => 6,
-        RType::SRA => 7,
-    }
-}
-
-fn jtype_to_int(jtype: &JType) -> u16 {
-    match jtype {
-        JType::Jmp => 1,
-        JType::Jal => 2,
-    }
-}
-
-fn itype_to_int(itype: &IType) -> u16 {
-    match itype {
-        IType::LW => 3,
-        IType::SW => 4,
-        IType::BEQ => 5,
-        IType::BNE => 6,
-        IType::AddI => 7,
-        IType::JmpI => 8,
-        IType::JalI => 9,
-        IType::LLI => 10,
-        IType::LUI => 11,
-    }
-}
-
-fn reg_to_int(reg: &Register) -> u16 {
-    match reg {
-        Register::Zero => 0,
-        Register::At => 1,
-        Register::V0 => 2,
-        Register::V1 => 3,
-        Register::A0 => 4,
-        Register::A1 => 5,
-        Register::Sp => 6,
-        Register::Ra => 7,
-        Register::None => 0,
-    }
+fn reg_to_int(reg: &Register, isa: &Isa) -> u16 {
+    isa.register_number(&reg.to_string())
 }
 
 /*
@@ -263,7 +735,13 @@ pub fn parse_line(raw_line: &str) -> IRInstruction {
 
 fn parse_instruction(instruction: &str, args: &Vec<&str>) -> IRInstruction {
     match instruction {
-        "add" | "sub" | "and" | "or" | "nor" | "sll" | "srl" | "sra" => {
+        // every r-type instruction takes three registers, so the ones an
+        // ISA description adds parse like add; other formats' mnemonics
+        // fall through to their own operands
+        _ if args.len() == 3 && args.iter().all(|arg| arg.starts_with("$"))
+            && !known_mnemonics(Format::J).contains(&instruction)
+            && !known_mnemonics(Format::I).contains(&instruction)
+            && !known_mnemonics(Format::IWide).contains(&instruction) => {
             let r1 = args.get(0).unwrap();
             let r2 = args.get(1).unwrap();
             let r3 = args.get(2).unwrap();
@@ -289,7 +767,7 @@ fn parse_instruction(instruction: &str, args: &Vec<&str>) -> IRInstruction {
         "lw" | "sw" => {
             // ex instruction: lw $v0, 2($a0)
             let im_and_reg: Vec<&str> = args.get(1).unwrap().split("(").collect();
-            let im = parse_num(im_and_reg.get(0).unwrap()) as i8;
+            let im = parse_immediate(im_and_reg.get(0).unwrap(), IMM_BITS) as i8;
             let reg = &im_and_reg.get(1).unwrap()[..im_and_reg.get(1).unwrap().len()-1];
             IRInstruction::IType(
                 parse_itype(instruction),
@@ -298,35 +776,59 @@ fn parse_instruction(instruction: &str, args: &Vec<&str>) -> IRInstruction {
                 im
             )
         },
+        "beq" | "bne" if !is_number(args.get(2).unwrap()) => {
+            // ex instruction: bne $v0, $a0, loop
+            IRInstruction::ITypeLabel(
+                parse_itype(instruction),
+                parse_register(&args.get(0).unwrap()[..args.get(0).unwrap().len()-1]),
+                parse_register(&args.get(1).unwrap()[..args.get(1).unwrap().len()-1]),
+                args.get(2).unwrap().to_string()
+            )
+        },
         "beq" | "bne" | "addi" => {
             // ex instruction: bne $v0, $a0, 2
             IRInstruction::IType(
                 parse_itype(instruction),
                 parse_register(&args.get(0).unwrap()[..args.get(0).unwrap().len()-1]),
                 parse_register(&args.get(1).unwrap()[..args.get(1).unwrap().len()-1]),
-                parse_num(args.get(2).unwrap()) as i8
+                parse_immediate(args.get(2).unwrap(), IMM_BITS) as i8
             )
         },
         "jmpi" | "jali" => {
             // ex instruction: jmpi 2($v0)
             let im_and_reg: Vec<&str> = args.get(0).unwrap().split("(").collect();
-            let im = parse_num(im_and_reg.get(0).unwrap()) as i8;
+            let im = parse_immediate(im_and_reg.get(0).unwrap(), WIDE_IMM_BITS);
             let reg = &im_and_reg.get(1).unwrap()[..im_and_reg.get(1).unwrap().len()-1];
-            IRInstruction::IType(
+            IRInstruction::ITypeWide(
                 parse_itype(instruction),
                 parse_register(reg),
-                Register::None,
                 im
             )
         },
+        "lli" | "lui" if args.get(1).unwrap().starts_with("%") => {
+            // ex instruction: lui $v0, %hi(msg)
+            let arg = args.get(1).unwrap();
+            let half = match &arg[..3] {
+                "%hi" => Half::Hi,
+                "%lo" => Half::Lo,
+                _ => panic!("Unrecognized operator in {}, expected %hi or %lo", arg),
+            };
+            if !arg[3..].starts_with("(") || !arg.ends_with(")") {
+                panic!("Expected a label in parentheses in {}", arg);
+            }
+            IRInstruction::ITypeWideLabel(
+                parse_itype(instruction),
+                parse_register(&args.get(0).unwrap()[..args.get(0).unwrap().len()-1]),
+                half,
+                arg[4..arg.len()-1].to_string()
+            )
+        },
         "lli" | "lui" => {
             // ex instruction: lli $v0, 2
-            IRInstruction::IType(
+            IRInstruction::ITypeWide(
                 parse_itype(instruction),
                 parse_register(&args.get(0).unwrap()[..args.get(0).unwrap().len()-1]),
-                Register::None,
-                parse_num(args.get(1).unwrap()) as i8
-
+                parse_byte(args.get(1).unwrap())
             )
         },
         "li" => {
@@ -339,7 +841,7 @@ fn parse_instruction(instruction: &str, args: &Vec<&str>) -> IRInstruction {
     }
 }
 
-fn parse_register(register: &str) -> Register {
+pub fn parse_register(register: &str) -> Register {
     match register {
         "$0" => Register::Zero,
         "$at" => Register::At,
@@ -349,6 +851,7 @@ fn parse_register(register: &str) -> Register {
         "$a1" => Register::A1,
         "$sp" => Register::Sp,
         "$ra" => Register::Ra,
+        other if other.len() > 1 && other.starts_with("$") => Register::Named(other.to_string()),
         _ => panic!("Unrecognized register type {}", register),
     }
 }
@@ -378,11 +881,15 @@ fn parse_directive(directive: &str, args: &Vec<&str>) -> Directive {
                 let new_counter = parse_num_unsigned(args.get(0).unwrap());
                 Directive::Org(new_counter)
             },
+            "text" | "data" | "bss" => Directive::Section(format!(".{}", directive)),
+            "section" => Directive::Section(args.get(0).unwrap().to_string()),
+            "globl" => Directive::Globl(args.get(0).unwrap().to_string()),
+            "extern" => Directive::Extern(args.get(0).unwrap().to_string()),
             _ => panic!("Unexpected directive keyword {}", directive),
         }
 }
 
-fn parse_rtype(instruction: &str) -> RType {
+pub fn parse_rtype(instruction: &str) -> RType {
     match instruction {
         "add" => RType::Add,
         "sub" => RType::Sub,
@@ -392,11 +899,11 @@ fn parse_rtype(instruction: &str) -> RType {
         "sll" => RType::SLL,
         "srl" => RType::SRL,
         "sra" => RType::SRA,
-        _ => panic!("Unrecognized r-type instruction {}", instruction),
+        other => RType::Named(other.to_string()),
     }
 }
 
-fn parse_jtype(instruction: &str) -> JType {
+pub fn parse_jtype(instruction: &str) -> JType {
     match instruction {
         "jmp" => JType::Jmp,
         "jal" => JType::Jal,
@@ -404,7 +911,7 @@ fn parse_jtype(instruction: &str) -> JType {
     }
 }
 
-fn parse_itype(instruction: &str) -> IType {
+pub fn parse_itype(instruction: &str) -> IType {
     match instruction {
         "lw" => IType::LW,
         "sw" => IType::SW,
@@ -423,13 +930,13 @@ fn parse_pseudo(instruction: &str, args: &Vec<&str>) -> Pseudo {
     match instruction {
         "li" => Pseudo::LI(
             parse_register(&args.get(0).unwrap()[..args.get(0).unwrap().len()-1]),
-            parse_num(&args.get(1).unwrap())
+            parse_constant(&args.get(1).unwrap())
         ),
         _ => panic!("Unrecognized pseudo instruction {}", instruction),
     }
 }
 
-fn parse_num_unsigned(expr: &str) -> u16 {
+pub fn parse_num_unsigned(expr: &str) -> u16 {
     if expr.starts_with("0x") {
         u16::from_str_radix(expr.trim_start_matches("0x"), 16)
             .expect(&format!("Error parsing number: {}", expr))
@@ -439,6 +946,62 @@ fn parse_num_unsigned(expr: &str) -> u16 {
     }
 }
 
+/*
+    Parses an immediate, checking that it fits in a signed field of the
+    given number of bits.
+*/
+fn parse_immediate(expr: &str, bits: u32) -> i16 {
+    let value = parse_num(expr);
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    if value < min || value > max {
+        panic!("Immediate {} does not fit in {} signed bits ({} to {})", expr, bits, min, max);
+    }
+    value
+}
+
+/*
+    Parses the byte operand of lli/lui, which only use the low 8 bits of
+    their immediate, as either a signed or an unsigned byte.
+*/
+fn parse_byte(expr: &str) -> i16 {
+    let value = parse_num_wide(expr);
+    if value < i8::MIN as i32 || value > u8::MAX as i32 {
+        panic!("Immediate {} does not fit in a byte (-128 to 255)", expr);
+    }
+    value as i16
+}
+
+/*
+    Parses a 16-bit constant written either signed or unsigned, returning
+    its bit pattern.
+*/
+fn parse_constant(expr: &str) -> i16 {
+    let value = parse_num_wide(expr);
+    if value < i16::MIN as i32 || value > u16::MAX as i32 {
+        panic!("Constant {} does not fit in 16 bits ({} to {})", expr, i16::MIN, u16::MAX);
+    }
+    value as u16 as i16
+}
+
+fn parse_num_wide(expr: &str) -> i32 {
+    let (negative, digits) = match expr.starts_with("-") {
+        true => (true, &expr[1..]),
+        false => (false, expr),
+    };
+    let magnitude = if digits.starts_with("0x") {
+        i32::from_str_radix(&digits[2..], 16)
+    } else {
+        i32::from_str_radix(digits, 10)
+    };
+    let magnitude = magnitude.expect(&format!("Error parsing number: {}", expr));
+    if negative { -magnitude } else { magnitude }
+}
+
+fn is_number(expr: &str) -> bool {
+    expr.trim_start_matches("-").chars().next().map_or(false, |c| c.is_ascii_digit())
+}
+
 fn parse_num(expr: &str) -> i16 {
     if expr.starts_with("0x") {
         i16::from_str_radix(expr.trim_start_matches("0x"), 16)
@@ -451,7 +1014,7 @@ fn parse_num(expr: &str) -> i16 {
 
 #[cfg(test)]
 mod tests {
-    use crate::{assembler::parse_line, ir::*};
+    use crate::{assembler::parse_line, assembler::instr_to_mif, assembler::assemble, assembler::expand_li, assembler::Options, ir::*, isa::Isa, memory::MemoryLayout};
 
     #[test]
     fn parse_empty_line() {
@@ -487,6 +1050,8 @@ mod tests {
         assert_eq!(parse_line(".byte 1"), IRInstruction::Directive(Directive::Byte(1)));
         assert_eq!(parse_line(".asciiz \"abc\""), IRInstruction::Directive(Directive::Asciiz(String::from("abc"))));
         assert_eq!(parse_line(".asciiz \"abc\""), IRInstruction::Directive(Directive::Asciiz(String::from("abc"))));
+        assert_eq!(parse_line(".globl main"), IRInstruction::Directive(Directive::Globl(String::from("main"))));
+        assert_eq!(parse_line(".extern print"), IRInstruction::Directive(Directive::Extern(String::from("print"))));
     }
 
     #[test]
@@ -543,32 +1108,38 @@ mod tests {
     fn parse_itype_jump() {
         assert_eq!(
             parse_line("jmpi 2($v0)"), 
-            IRInstruction::IType(IType::JmpI, Register::V0, Register::None, 2));
+            IRInstruction::ITypeWide(IType::JmpI, Register::V0, 2));
         assert_eq!(
             parse_line("jmpi 0xA($v0)"), 
-            IRInstruction::IType(IType::JmpI, Register::V0, Register::None, 10));
+            IRInstruction::ITypeWide(IType::JmpI, Register::V0, 10));
         assert_eq!(
             parse_line("jali 2($v0)"), 
-            IRInstruction::IType(IType::JalI, Register::V0, Register::None, 2));
+            IRInstruction::ITypeWide(IType::JalI, Register::V0, 2));
         assert_eq!(
             parse_line("jali 0xA($v0)"), 
-            IRInstruction::IType(IType::JalI, Register::V0, Register::None, 10));
+            IRInstruction::ITypeWide(IType::JalI, Register::V0, 10));
+        assert_eq!(
+            parse_line("jali -256($v0)"), 
+            IRInstruction::ITypeWide(IType::JalI, Register::V0, -256));
+        assert_eq!(
+            parse_line("jmpi 255($v0)"), 
+            IRInstruction::ITypeWide(IType::JmpI, Register::V0, 255));
     }
 
     #[test]
     fn parse_itype_one_reg_and_immediate() {
         assert_eq!(
             parse_line("lli $v0, 2"), 
-            IRInstruction::IType(IType::LLI, Register::V0, Register::None, 2));
+            IRInstruction::ITypeWide(IType::LLI, Register::V0, 2));
         assert_eq!(
             parse_line("lli $v0, 0xA"), 
-            IRInstruction::IType(IType::LLI, Register::V0, Register::None, 10));
+            IRInstruction::ITypeWide(IType::LLI, Register::V0, 10));
         assert_eq!(
             parse_line("lui $v0, 2"), 
-            IRInstruction::IType(IType::LUI, Register::V0, Register::None, 2));
+            IRInstruction::ITypeWide(IType::LUI, Register::V0, 2));
         assert_eq!(
             parse_line("lui $v0, 0xA"), 
-            IRInstruction::IType(IType::LUI, Register::V0, Register::None, 10));
+            IRInstruction::ITypeWide(IType::LUI, Register::V0, 10));
     }
 
     #[test]
@@ -579,5 +1150,298 @@ mod tests {
         assert_eq!(
             parse_line("li $v0, -256"), 
             IRInstruction::Pseudo(Pseudo::LI(Register::V0, -256)));
+        assert_eq!(
+            parse_line("li $v0, 0xFF08"), 
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 0xFF08u16 as i16)));
+        assert_eq!(
+            parse_line("li $v0, -32768"), 
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, -32768)));
+    }
+
+    #[test]
+    fn encode_with_loaded_isa() {
+        let isa = Isa::load("[rtype]\nopcode = 0b1111\nsub = 0b101\n[itype]\naddi = 0b0001\n");
+        assert_eq!(
+            instr_to_mif(&0, &parse_line("sub $v0, $a0, $a1"), &isa),
+            "0 : F52D; -- sub $v0, $a0, $a1");
+        assert_eq!(
+            instr_to_mif(&0, &parse_line("addi $v0, $a0, 2"), &isa),
+            "0 : 1502; -- addi $v0, $a0, 2");
+    }
+
+    #[test]
+    fn encode_registers_and_functs_the_isa_declares() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n[registers]\n$0 = 0\n$t0 = 1\n");
+        assert_eq!(parse_line("xor $t0, $0, $t0"),
+            IRInstruction::RType(RType::Named(String::from("xor")), Register::Named(String::from("$t0")),
+                Register::Zero, Register::Named(String::from("$t0"))));
+        assert_eq!(
+            instr_to_mif(&0, &parse_line("xor $t0, $0, $t0"), &isa),
+            "0 : 20D; -- xor $t0, $0, $t0");
+    }
+
+    #[test]
+    #[should_panic(expected = "Error parsing number: $a1")]
+    fn itypes_do_not_take_three_registers() {
+        parse_line("addi $v0, $a0, $a1");
+    }
+
+    #[test]
+    #[should_panic(expected = "Unrecognized instruction inc with three registers")]
+    fn new_itypes_do_not_take_three_registers() {
+        let isa = Isa::load("[itype]\nnew inc = 13\n");
+        instr_to_mif(&0, &parse_line("inc $v0, $a0, $a1"), &isa);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in 9 signed bits")]
+    fn parse_itype_jump_out_of_range() {
+        parse_line("jmpi 256($v0)");
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in 6 signed bits")]
+    fn parse_itype_immediate_out_of_range() {
+        parse_line("addi $v0, $a0, -33");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn encode_itype_jump() {
+        assert_eq!(
+            instr_to_mif(&0, &parse_line("jali -1($ra)"), &Isa::default()),
+            "0 : 9FFF; -- jali -1($ra)");
+        assert_eq!(
+            instr_to_mif(&0, &parse_line("jmpi 0xFF($v0)"), &Isa::default()),
+            "0 : 84FF; -- jmpi 255($v0)");
+    }
+
+    #[test]
+    fn labels_and_blank_lines_take_no_space() {
+        assert_eq!(
+            assemble("# comment\n\nstart:\nadd $v0, $a0, $a1\nend:\njmp start\njmp end\n", &Options::default()).instrs,
+            vec![
+                (0, IRInstruction::RType(RType::Add, Register::V0, Register::A0, Register::A1)),
+                (1, IRInstruction::JTypeAddr(JType::Jmp, 0)),
+                (2, IRInstruction::JTypeAddr(JType::Jmp, 1)),
+            ]);
+    }
+
+    #[test]
+    fn jump_within_region() {
+        assert_eq!(
+            assemble(".org 0x1FFE\njmp far\nfar:\n", &Options::default()).instrs,
+            vec![(0x1FFE, IRInstruction::JTypeAddr(JType::Jmp, 0xFFF))]);
+        assert_eq!(
+            assemble(".org 0x1FFF\njal back\nback:\n", &Options::default()).instrs,
+            vec![(0x1FFF, IRInstruction::JTypeAddr(JType::Jal, 0))]);
+    }
+
+    #[test]
+    #[should_panic(expected = "outside its 8K region")]
+    fn jump_out_of_region() {
+        assemble("jmp far\n.org 0x1000\nfar:\n", &Options::default());
+    }
+
+    #[test]
+    fn relax_far_jumps() {
+        let relax = Options { relax: true, ..Options::default() };
+        assert_eq!(
+            assemble("jal far\n.org 0x1000\nfar:\n", &relax).instrs,
+            vec![
+                (0, IRInstruction::ITypeWide(IType::LLI, Register::At, 0)),
+                (1, IRInstruction::ITypeWide(IType::LUI, Register::At, 0x20)),
+                (2, IRInstruction::ITypeWide(IType::JalI, Register::At, 0)),
+            ]);
+        // growing the first jump pushes the second one's target out of its region
+        assert_eq!(
+            assemble(".org 0xFFB\njmp far\njmp next\n.space 2\nnext:\n.org 0x2000\nfar:\n", &relax).instrs,
+            vec![
+                (0xFFB, IRInstruction::ITypeWide(IType::LLI, Register::At, 0)),
+                (0xFFC, IRInstruction::ITypeWide(IType::LUI, Register::At, 0x40)),
+                (0xFFD, IRInstruction::ITypeWide(IType::JmpI, Register::At, 0)),
+                (0xFFE, IRInstruction::ITypeWide(IType::LLI, Register::At, 0x06)),
+                (0xFFF, IRInstruction::ITypeWide(IType::LUI, Register::At, 0x20)),
+                (0x1000, IRInstruction::ITypeWide(IType::JmpI, Register::At, 0)),
+            ]);
+    }
+
+    #[test]
+    fn parse_itype_branch_label() {
+        assert_eq!(
+            parse_line("beq $v0, $a0, loop"), 
+            IRInstruction::ITypeLabel(IType::BEQ, Register::V0, Register::A0, String::from("loop")));
+        assert_eq!(
+            parse_line("bne $v0, $a0, -3"), 
+            IRInstruction::IType(IType::BNE, Register::V0, Register::A0, -3));
+    }
+
+    #[test]
+    fn branch_to_label() {
+        assert_eq!(
+            assemble("loop:\nbeq $v0, $a0, done\nbne $v0, $0, loop\ndone:\n", &Options::default()).instrs,
+            vec![
+                (0, IRInstruction::IType(IType::BEQ, Register::V0, Register::A0, 1)),
+                (1, IRInstruction::IType(IType::BNE, Register::V0, Register::Zero, -2)),
+            ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "beyond the branch range")]
+    fn branch_out_of_range() {
+        assemble("beq $v0, $a0, far\n.space 32\nfar:\n", &Options::default());
+    }
+
+    #[test]
+    fn relax_branches() {
+        let relax = Options { relax: true, ..Options::default() };
+        assert_eq!(
+            assemble("beq $v0, $a0, far\n.space 31\nfar:\n", &relax).instrs,
+            vec![(0, IRInstruction::IType(IType::BEQ, Register::V0, Register::A0, 31))]);
+        assert_eq!(
+            assemble("beq $v0, $a0, far\n.space 32\nfar:\n", &relax).instrs,
+            vec![
+                (0, IRInstruction::IType(IType::BNE, Register::V0, Register::A0, 1)),
+                (1, IRInstruction::JTypeAddr(JType::Jmp, 34)),
+            ]);
+        assert_eq!(
+            assemble("bne $v0, $a0, far\n.org 0x1000\nfar:\n", &relax).instrs,
+            vec![
+                (0, IRInstruction::IType(IType::BEQ, Register::V0, Register::A0, 3)),
+                (1, IRInstruction::ITypeWide(IType::LLI, Register::At, 0)),
+                (2, IRInstruction::ITypeWide(IType::LUI, Register::At, 0x20)),
+                (3, IRInstruction::ITypeWide(IType::JmpI, Register::At, 0)),
+            ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "The beq at 0x0 branches 1 words over the transfer at 0x1, which --relax made longer")]
+    fn relax_keeps_numeric_branches_exact() {
+        let relax = Options { relax: true, ..Options::default() };
+        let program = assemble("bne $v0, $a0, far\nbeq $v0, $a0, 1\n.space 40\nfar:\n", &relax);
+        assert_eq!(program.instrs[2], (2, IRInstruction::IType(IType::BEQ, Register::V0, Register::A0, 1)));
+        assemble("beq $v0, $a0, 1\nbne $v0, $a0, far\n.space 40\nfar:\n", &relax);
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in 16 bits")]
+    fn parse_pseudo_instr_out_of_range() {
+        parse_line("li $v0, 0x10000");
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit in a byte")]
+    fn parse_itype_byte_out_of_range() {
+        parse_line("lui $v0, 256");
+    }
+
+    #[test]
+    fn expand_li_matches_lli_lui_semantics() {
+        // LLI: $1 = sign-extended IMM[7:0], LUI: $1 = IMM[7:0] << 8 | $1[7:0]
+        for value in i16::MIN..=i16::MAX {
+            let mut reg: u16 = 0xDEAD;
+            let instrs = expand_li(0, &Register::V0, value);
+            for (_, instr) in instrs.iter() {
+                reg = match instr {
+                    IRInstruction::ITypeWide(IType::LLI, _, im) => *im as i8 as i16 as u16,
+                    IRInstruction::ITypeWide(IType::LUI, _, im) => ((*im as u16 & 0xFF) << 8) | (reg & 0xFF),
+                    _ => panic!("Unexpected instruction in li expansion {}", instr),
+                }
+            }
+            assert_eq!(reg, value as u16);
+            assert_eq!(instrs.len(), if value >= -128 && value <= 127 { 1 } else { 2 });
+        }
+    }
+
+    #[test]
+    fn li_shrinks_when_it_fits() {
+        assert_eq!(
+            assemble("li $v0, -5\nli $v1, 0xFF08\nadd $v0, $v0, $v1\n", &Options::default()).instrs,
+            vec![
+                (0, IRInstruction::ITypeWide(IType::LLI, Register::V0, -5)),
+                (1, IRInstruction::ITypeWide(IType::LLI, Register::V1, 8)),
+                (2, IRInstruction::ITypeWide(IType::LUI, Register::V1, 0xFF)),
+                (3, IRInstruction::RType(RType::Add, Register::V0, Register::V0, Register::V1)),
+            ]);
+    }
+
+    #[test]
+    fn optimize_keeps_labels_consistent() {
+        let optimize = Options { optimize: true, ..Options::default() };
+        assert_eq!(
+            assemble("li $v0, 100\naddi $v0, $v0, 31\njmp next\nnext:\nbeq $v0, $0, next\njmp next\n", &optimize).instrs,
+            vec![
+                (0, IRInstruction::ITypeWide(IType::LLI, Register::V0, -125)),
+                (1, IRInstruction::ITypeWide(IType::LUI, Register::V0, 0)),
+                (2, IRInstruction::IType(IType::BEQ, Register::V0, Register::Zero, -1)),
+                (3, IRInstruction::JTypeAddr(JType::Jmp, 2)),
+            ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined label prnt (did you mean print?)")]
+    fn undefined_label_suggests_similar() {
+        assemble("print:\njal prnt\n", &Options::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined label prnt in main.s (did you mean print?)")]
+    fn undefined_label_names_the_file() {
+        assemble("print:\njal prnt\n", &Options { file: String::from("main.s"), ..Options::default() });
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined label print")]
+    fn object_needs_extern() {
+        assemble("jal print\n", &Options { object: true, ..Options::default() });
+    }
+
+    #[test]
+    #[should_panic(expected = "main is declared .globl but never defined")]
+    fn globl_needs_definition() {
+        assemble(".globl main\n", &Options::default());
+    }
+
+    #[test]
+    fn sections_have_own_counters() {
+        let options = Options {
+            layout: MemoryLayout::load("[regions]\nrom = 0 0x100\nram = 0x100 0x100\n[sections]\n.text = rom\n.data = ram\n.bss = ram\n"),
+            ..Options::default()
+        };
+        let program = assemble(".data\ncount:\n.word 7\n.bss\nbuf:\n.space 4\n.text\nlli $v0, %lo(buf)\n.data\n.word 8\n", &options);
+        assert_eq!(program.symbols.get("count"), Some(&0x100));
+        assert_eq!(program.symbols.get("buf"), Some(&0x102));
+        assert_eq!(program.instrs, vec![
+            (0, IRInstruction::ITypeWide(IType::LLI, Register::V0, 4)),
+            (0x100, IRInstruction::Directive(Directive::Word(7))),
+            (0x101, IRInstruction::Directive(Directive::Word(8))),
+        ]);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot go in .bss")]
+    fn bss_holds_no_data() {
+        assemble(".bss\n.word 1\n", &Options::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Region memory holds 0x4000 words but its sections need 0x4001: .text 0x3000, .data 0x1001")]
+    fn sections_overflowing_memory() {
+        assemble(".space 0x3000\n.section .data\n.space 0x1001\n", &Options::default());
+    }
+
+    #[test]
+    fn display_parses_back() {
+        let sources = [
+            "main:", "add $v0, $a0, $a1", "sra $sp, $ra, $0", "jmp loop", "jal 0x1F",
+            "lw $v0, -2($sp)", "sw $a0, 31($at)", "beq $v0, $0, -32", "bne $v1, $a1, loop",
+            "addi $v0, $v0, 1", "jmpi -256($ra)", "jali 255($a0)", "lli $v0, -128", "lui $v1, 255",
+            "lli $a0, %lo(msg)", "lui $a0, %hi(msg)", "li $v0, -300", ".org 0x10", ".space 4",
+            ".byte 200", ".word 0xBEEF", ".asciiz \"hello world\"", ".globl main", ".extern print", ".data",
+        ];
+        for source in sources.iter() {
+            let instr = parse_line(source);
+            assert_eq!(instr.to_string(), *source);
+            assert_eq!(parse_line(&instr.to_string()), instr);
+        }
+    }
+}
@@ -0,0 +1,326 @@
+/*
+    The command line. The binary only hands its arguments to `run`, so a
+    program using the crate as a library can register its own output
+    formats and then do the same.
+*/
+
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::process;
+
+use archive;
+use assembler;
+use control;
+use debugger;
+use devices;
+use disasm;
+use isa;
+use linker;
+use memory;
+use multicycle;
+use object;
+use output;
+use reader;
+use simulator;
+use vectors;
+
+/*
+    Runs the command line given as `args`, the program name first.
+*/
+pub fn run(mut args: Vec<String>) {
+    let isa_text = take_option(&mut args, "--isa")
+        .map(|isa_file| fs::read_to_string(isa_file).expect("Unable to read given ISA file"));
+    if args.len() == 2 && args[1] == "check-isa" {
+        // check the table as written, so conflicting tables can be reported
+        let isa = match isa_text {
+            Some(text) => isa::Isa::parse(&text),
+            None => isa::Isa::default(),
+        };
+        let report = isa.check();
+        print!("{}", report);
+        if !report.is_clean() {
+            process::exit(1);
+        }
+        return;
+    }
+    let isa = match isa_text {
+        Some(text) => isa::Isa::load(&text),
+        None => isa::Isa::default(),
+    };
+    let layout = match take_option(&mut args, "--layout") {
+        Some(layout_file) => memory::MemoryLayout::load(
+            &fs::read_to_string(layout_file).expect("Unable to read given memory layout file")),
+        None => memory::MemoryLayout::default(),
+    };
+    let writer = output::writer(&take_option(&mut args, "--format").unwrap_or(String::from("mif")));
+    let depth = layout.words();
+    let options = assembler::Options {
+        relax: take_flag(&mut args, "--relax"),
+        optimize: take_flag(&mut args, "-O"),
+        object: take_flag(&mut args, "-c"),
+        layout,
+        write: output::WriteOptions {
+            byte_order: take_option(&mut args, "--byte-order").map_or(output::ByteOrder::default(), |name| output::ByteOrder::parse(&name)),
+            comments: take_flag(&mut args, "--comments"),
+            pad: take_option(&mut args, "--pad").map_or(0, |pad| assembler::parse_num_unsigned(&pad)),
+            depth,
+        },
+        file: String::new(),
+    };
+    let max_steps = take_option(&mut args, "--max-steps").map_or(1_000_000, |steps| steps.parse::<u64>().expect("Error parsing the step limit"));
+    let cycles_file = take_option(&mut args, "--cycles");
+    let input_file = take_option(&mut args, "--input");
+    let output_file = take_option(&mut args, "--output");
+    let input_at = take_option(&mut args, "--input-at").map_or(devices::INPUT_ADDRESS, |at| assembler::parse_num_unsigned(&at));
+    let output_at = take_option(&mut args, "--output-at").map_or(devices::OUTPUT_ADDRESS, |at| assembler::parse_num_unsigned(&at));
+    let random = take_option(&mut args, "--random").map(|count| count.parse::<u32>().expect("Error parsing the vector count"));
+    let seed = take_option(&mut args, "--seed").map_or(1, |seed| seed.parse::<u64>().expect("Error parsing the seed"));
+    let bits = take_option(&mut args, "--bits").map_or(4, |bits| bits.parse::<u32>().expect("Error parsing the operand width"));
+    let control_spec = match take_option(&mut args, "--control") {
+        Some(spec_file) => control::ControlSpec::parse(
+            &fs::read_to_string(spec_file).expect("Unable to read given control specification")),
+        None => control::ControlSpec::default(),
+    };
+    if args.len() >= 3 && args[1] == "control" {
+        for output_file in args[2..].iter() {
+            let text = if output_file.ends_with(".csv") {
+                control::csv(&control_spec.truth_table(&isa).unwrap_or_else(|msg| panic!("{}", msg)))
+            } else if output_file.ends_with(".md") {
+                control::markdown(&control_spec.truth_table(&isa).unwrap_or_else(|msg| panic!("{}", msg)))
+            } else if output_file.ends_with(".mif") {
+                control::rom_mif(&control_spec.rom(&isa).unwrap_or_else(|msg| panic!("{}", msg)), &isa)
+            } else {
+                panic!("Cannot tell what to write to {}; name it .csv or .md for the truth table or .mif for the ROM", output_file)
+            };
+            fs::write(output_file, text).expect("Unable to write the control unit tables");
+        }
+        return;
+    }
+    if args.len() == 4 && args[1] == "vectors" {
+        // CSV for files named .csv, $readmemh otherwise
+        let csv = args[3].ends_with(".csv");
+        let text = match args[2].as_str() {
+            "alu" => {
+                let operands = match random {
+                    Some(count) => vectors::Operands::Random(count, seed),
+                    None => vectors::Operands::Exhaustive(bits),
+                };
+                let alu_vectors = vectors::alu_vectors(&operands);
+                if csv { vectors::alu_csv(&alu_vectors) } else { vectors::alu_readmemh(&alu_vectors) }
+            },
+            "control" => {
+                let control_vectors = vectors::control_vectors(&isa, &control_spec).unwrap_or_else(|msg| panic!("{}", msg));
+                if csv { vectors::control_csv(&control_vectors) } else { vectors::control_readmemh(&control_vectors) }
+            },
+            other => panic!("Unrecognized test vector set {}; expected alu or control", other),
+        };
+        fs::write(&args[3], text).expect("Unable to write the test vectors");
+        return;
+    }
+    if args.len() == 4 && args[1] == "convert" {
+        let image = read_image_file(&args[2], options.write.byte_order);
+        fs::write(&args[3], writer.write(&image, &options.write))
+            .expect("Unable to write the converted image");
+        return;
+    }
+    if (args.len() == 3 || args.len() == 4) && args[1] == "disasm" {
+        let text = disasm::disassemble(&read_image_file(&args[2], options.write.byte_order), &isa);
+        match args.get(3) {
+            Some(output_file) => fs::write(output_file, text).expect("Unable to write the disassembly"),
+            None => print!("{}", text),
+        }
+        return;
+    }
+    if args.len() == 3 && args[1] == "run" {
+        let mut machine = simulator::Machine::new(&isa);
+        machine.load(&program_image(&args[2], &isa, &options));
+        let input: Box<dyn io::Read> = match &input_file {
+            Some(file) => Box::new(fs::File::open(file).expect("Unable to open the input device file")),
+            None => Box::new(io::stdin()),
+        };
+        let output: Box<dyn io::Write> = match &output_file {
+            Some(file) => Box::new(fs::File::create(file).expect("Unable to create the output device file")),
+            None => Box::new(io::stdout()),
+        };
+        machine.attach(input_at, Box::new(devices::ConsoleInput::new(input)));
+        machine.attach(output_at, Box::new(devices::ConsoleOutput::new(output)));
+        match &cycles_file {
+            Some(file) => {
+                let mut model = multicycle::Multicycle::new(machine, control_spec);
+                model.run(max_steps).unwrap_or_else(|fault| panic!("{}", fault));
+                fs::write(file, model.trace_csv()).expect("Unable to write the cycle trace");
+                print!("{}", model.machine.dump_registers());
+                println!("{} cycles", model.cycles);
+            },
+            None => {
+                machine.run(max_steps).unwrap_or_else(|fault| panic!("{}", fault));
+                print!("{}", machine.dump_registers());
+            },
+        }
+        return;
+    }
+    if args.len() == 3 && args[1] == "debug" {
+        let image = program_image(&args[2], &isa, &options);
+        let mut machine = simulator::Machine::new(&isa);
+        machine.load(&image);
+        // commands come from stdin, so the program only reads a given file
+        let input: Box<dyn io::Read> = match &input_file {
+            Some(file) => Box::new(fs::File::open(file).expect("Unable to open the input device file")),
+            None => Box::new(io::empty()),
+        };
+        let output: Box<dyn io::Write> = match &output_file {
+            Some(file) => Box::new(fs::File::create(file).expect("Unable to create the output device file")),
+            None => Box::new(io::stdout()),
+        };
+        machine.attach(input_at, Box::new(devices::ConsoleInput::new(input)));
+        machine.attach(output_at, Box::new(devices::ConsoleOutput::new(output)));
+        let mut debugger = debugger::Debugger::new(machine, image.symbols.clone(), max_steps);
+        print!("{}", debugger.list());
+        let stdin = io::stdin();
+        let mut lines = stdin.lock().lines();
+        loop {
+            print!("(debug) ");
+            io::Write::flush(&mut io::stdout()).expect("Unable to write the prompt");
+            let line = match lines.next() {
+                Some(line) => line.expect("Unable to read a command"),
+                None => break,
+            };
+            let (text, done) = debugger.command(&line);
+            print!("{}", text);
+            if done {
+                break;
+            }
+        }
+        return;
+    }
+    if args.len() >= 3 && args[1] == "archive" {
+        let members = args[3..]
+            .iter()
+            .map(|file| {
+                let contents = fs::read_to_string(file).expect("Unable to read given object file");
+                let name = Path::new(file).file_name().unwrap().to_string_lossy().to_string();
+                (name, object::Object::parse(&contents))
+            })
+            .collect();
+        fs::write(&args[2], archive::Archive { members }.to_string())
+            .expect("Unable to write the archive");
+        return;
+    }
+    if args.len() >= 3 && args[1] == "link" {
+        let mut objects: Vec<(String, object::Object)> = Vec::new();
+        let mut archives: Vec<(String, archive::Archive)> = Vec::new();
+        for file in args[3..].iter() {
+            let contents = fs::read_to_string(file).expect("Unable to read given object file");
+            if archive::Archive::is_archive(&contents) {
+                archives.push((file.clone(), archive::Archive::parse(&contents)));
+            } else {
+                objects.push((file.clone(), object::Object::parse(&contents)));
+            }
+        }
+        let members = linker::pull_members(&objects, &archives);
+        objects.extend(members);
+        let (words, symbols) = linker::link(&objects, &isa, &options.layout);
+        let image = output::Image::from_words(words, &symbols);
+        fs::write(&args[2], writer.write(&image, &options.write))
+            .expect("Unable to write the linked image");
+        return;
+    }
+    let extension = if options.object { ".o" } else { writer.extension() };
+    match args.len() {
+        2 => {
+            let arg1 = &args[1];
+            if arg1 == "--repl" {
+                let stdin = io::stdin();
+                for line in stdin.lock().lines() {
+                    println!("{}", assembler::instr_to_mif(&0, &assembler::parse_line(&line.unwrap()), &isa));
+                }
+            } else {
+                let contents = fs::read_to_string(arg1)
+                    .expect("Unable to read given file");
+                let options = assembler::Options { file: arg1.clone(), ..options };
+                assembler::parse(contents, arg1.to_owned() + extension, &isa, &options, writer.as_ref())
+                    .expect("Unable to parse the given file")
+            }
+        },
+        3 => {
+            let in_file_name = &args[1];
+            let out_file_name = &args[2];
+            let contents = fs::read_to_string(in_file_name )
+                .expect("Unable to read given file");
+            let options = assembler::Options { file: in_file_name.clone(), ..options };
+            assembler::parse(contents, out_file_name.to_owned(), &isa, &options, writer.as_ref())
+                .expect("Unable to parse the given file")
+        }
+        _ => help()
+    }
+}
+
+/*
+    Reads a raw binary image named by its .bin extension, or else a MIF or
+    Intel HEX image.
+*/
+fn read_image_file(file: &str, order: output::ByteOrder) -> output::Image {
+    if file.ends_with(".bin") {
+        reader::read_binary(&fs::read(file).expect("Unable to read given image file"), order)
+    } else {
+        reader::read_image(&fs::read_to_string(file).expect("Unable to read given image file"), order)
+    }
+}
+
+/*
+    Reads a MIF, Intel HEX or raw binary image, or assembles source given
+    by any other file name.
+*/
+fn program_image(file: &str, isa: &isa::Isa, options: &assembler::Options) -> output::Image {
+    if file.ends_with(".mif") || file.ends_with(".hex") || file.ends_with(".bin") {
+        read_image_file(file, options.write.byte_order)
+    } else {
+        let contents = fs::read_to_string(file).expect("Unable to read given program file");
+        let options = assembler::Options { file: file.to_string(), ..options.clone() };
+        assembler::image(&assembler::assemble(&contents, &options), isa)
+    }
+}
+
+/*
+    Removes `name value` from the arguments, returning the value if the
+    option was given.
+*/
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let idx = args.iter().position(|arg| arg == name)?;
+    if idx + 1 >= args.len() {
+        panic!("Missing value for option {}", name);
+    }
+    let value = args.remove(idx + 1);
+    args.remove(idx);
+    Some(value)
+}
+
+/*
+    Removes the flag from the arguments, returning whether it was given.
+*/
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(idx) => {
+            args.remove(idx);
+            true
+        },
+        None => false,
+    }
+}
+
+fn help() {
+    println!("Usage: ./main [ --isa isa_file ]? [ --layout layout_file ]? [ --relax ]? [ -O ]? [ -c ]? [ --format format ]? [ --byte-order big|little ]? [ --comments ]? [ --pad word ]? [ input_file | --repl ] [ output_file ]?");
+    println!("       ./main [ --isa isa_file ]? check-isa");
+    println!("       ./main [ --isa isa_file ]? [ --layout layout_file ]? [ --format format ]? [ --byte-order big|little ]? [ --comments ]? [ --pad word ]? link output_file [ object_file | archive_file ]+");
+    println!("       ./main archive archive_file object_file+");
+    println!("       ./main [ --format format ]? [ --byte-order big|little ]? convert image_file output_file");
+    println!("       ./main [ --isa isa_file ]? [ --byte-order big|little ]? disasm image_file [ output_file ]?");
+    println!("       ./main [ --isa isa_file ]? [ --layout layout_file ]? [ --byte-order big|little ]? [ --max-steps steps ]? [ --input file ]? [ --output file ]? [ --input-at address ]? [ --output-at address ]? [ --cycles trace_file [ --control control_file ]? ]? run [ image_file | source_file ]");
+    println!("       ./main [ --isa isa_file ]? [ --layout layout_file ]? [ --byte-order big|little ]? [ --max-steps steps ]? [ --input file ]? [ --output file ]? [ --input-at address ]? [ --output-at address ]? debug [ image_file | source_file ]");
+    println!("       ./main [ --isa isa_file ]? [ --control control_file ]? control [ table.csv | table.md | rom.mif ]+");
+    println!("       ./main [ --isa isa_file ]? [ --control control_file ]? [ --bits bits | --random count [ --seed seed ]? ]? vectors alu|control output_file");
+    let names: Vec<&str> = output::writers().iter().map(|writer| writer.name()).collect();
+    println!("Formats: {} (default {})", names.join(", "), names[0]);
+    println!("Image files: .mif, Intel HEX (.hex) or raw binary (.bin)")
+}
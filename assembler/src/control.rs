@@ -0,0 +1,406 @@
+/*
+    Control-unit truth tables and microcode ROM from the instruction table
+    and a control specification, which gives the control lines of each
+    state per format or instruction. examples/control/default.control
+    describes the format and is the built-in specification; the multicycle
+    model runs whichever specification it is given, so the tables, ROM and
+    test vectors describe the control unit it simulates.
+
+    The ROM is addressed by the control unit's inputs and holds the
+    control word of multicycle.rs followed by the next state:
+
+        address   opcode funct state[3:1] zero[0]
+        data      control_word[18:3] next_state[2:0]
+
+    The opcode and funct are as wide as those fields of the ISA's r-type
+    layout, giving opcode[10:7] funct[6:4] by default. Every opcode fetches
+    and decodes alike, since the IR is decoded at the end of decode;
+    opcodes the ISA leaves unused go back to fetch after decode, and the
+    remaining addresses hold 0.
+*/
+
+use alu::alu_function;
+use alu::AluFunction;
+use isa::Encoding;
+use isa::Format;
+use isa::Isa;
+use multicycle::ControlLines;
+use multicycle::MemAccess;
+use multicycle::MemAddr;
+use multicycle::PcSource;
+use multicycle::RegAccess;
+use multicycle::RegSource;
+use multicycle::SecondOp;
+use multicycle::State;
+
+const STATES: [State; 5] = [State::Fetch, State::Decode, State::Execute, State::Memory, State::Writeback];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub state: State,
+    pub selectors: Vec<String>, // formats, mnemonics or all
+    pub settings: Vec<(String, String)>, // control line and value
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlSpec {
+    pub rules: Vec<Rule>,
+}
+
+/*
+    One row of the truth table; zero is None when the lines don't depend
+    on it.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub mnemonic: String,
+    pub opcode: u16,
+    pub funct: u16,
+    pub state: State,
+    pub zero: Option<bool>,
+    pub lines: ControlLines,
+    pub next: State,
+}
+
+impl Default for ControlSpec {
+    fn default() -> ControlSpec {
+        ControlSpec::parse(include_str!("../examples/control/default.control"))
+    }
+}
+
+impl ControlSpec {
+    pub fn parse(text: &str) -> ControlSpec {
+        let mut rules: Vec<Rule> = Vec::new();
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line = match raw_line.find("#") {
+                None => raw_line.trim(),
+                Some(i) => raw_line[..i].trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("[") && line.ends_with("]") {
+                let parts: Vec<&str> = line[1..line.len()-1].splitn(2, ":").collect();
+                if parts.len() != 2 || parts[1].trim().is_empty() {
+                    panic!("Expected [state: instructions] on line {}: {}", idx + 1, raw_line);
+                }
+                let state = match parts[0].trim() {
+                    "fetch" => State::Fetch,
+                    "decode" => State::Decode,
+                    "execute" => State::Execute,
+                    "memory" => State::Memory,
+                    "writeback" => State::Writeback,
+                    other => panic!("Unrecognized state {} on line {}", other, idx + 1),
+                };
+                let selectors = parts[1].split_whitespace().map(|s| s.to_string()).collect();
+                rules.push(Rule { state, selectors, settings: vec![] });
+                continue;
+            }
+            let parts: Vec<&str> = line.split("=").map(|s| s.trim()).collect();
+            if parts.len() != 2 || parts[0].is_empty() {
+                panic!("Expected `ControlLine = value` on line {}: {}", idx + 1, raw_line);
+            }
+            let rule = match rules.last_mut() {
+                Some(rule) => rule,
+                None => panic!("Expected a [state: instructions] section before line {}", idx + 1),
+            };
+            // check the setting against an instruction that takes any value
+            if let Err(msg) = set(&mut ControlLines::default(), parts[0], parts[1], "add", false) {
+                panic!("{} on line {}", msg, idx + 1);
+            }
+            rule.settings.push((parts[0].to_string(), parts[1].to_string()));
+        }
+        ControlSpec { rules }
+    }
+
+    /*
+        The states an instruction goes through, in order.
+    */
+    pub fn states(&self, encoding: &Encoding) -> Vec<State> {
+        STATES
+            .iter()
+            .cloned()
+            .filter(|state| self.rules.iter().any(|rule| rule.state == *state && selects(rule, Some(encoding))))
+            .collect()
+    }
+
+    pub fn next_state(&self, encoding: &Encoding, state: State) -> State {
+        let states = self.states(encoding);
+        match states.iter().position(|s| *s == state) {
+            Some(i) if i + 1 < states.len() => states[i + 1],
+            _ => State::Fetch,
+        }
+    }
+
+    /*
+        Control lines of a state of the given instruction. Before the IR
+        is decoded at the end of decode there is no instruction, and only
+        the sections for all apply. zero is the ALU's Zero status line,
+        which decides whether a branch is taken. Fails if a line takes the
+        instruction's ALU function and the ALU has none for it.
+    */
+    pub fn lines(&self, encoding: Option<&Encoding>, state: State, zero: bool) -> Result<ControlLines, String> {
+        let mnemonic = encoding.map_or("", |encoding| encoding.mnemonic.as_str());
+        let mut lines = ControlLines::default();
+        for rule in self.rules.iter().filter(|rule| rule.state == state && selects(rule, encoding)) {
+            for (name, value) in rule.settings.iter() {
+                set(&mut lines, name, value, mnemonic, zero)?;
+            }
+        }
+        Ok(lines)
+    }
+
+    /*
+        The rows of every state of every instruction, split on the Zero
+        status line only where it changes the lines.
+    */
+    pub fn truth_table(&self, isa: &Isa) -> Result<Vec<Row>, String> {
+        let mut rows = Vec::new();
+        for encoding in isa.instructions.iter() {
+            for state in self.states(encoding) {
+                let (clear, set) = (self.lines(Some(encoding), state, false)?, self.lines(Some(encoding), state, true)?);
+                let zeros = if clear == set { vec![(None, clear)] } else { vec![(Some(false), clear), (Some(true), set)] };
+                for (zero, lines) in zeros {
+                    rows.push(Row {
+                        mnemonic: encoding.mnemonic.clone(),
+                        opcode: encoding.opcode,
+                        funct: encoding.funct,
+                        state,
+                        zero,
+                        lines,
+                        next: self.next_state(encoding, state),
+                    });
+                }
+            }
+        }
+        Ok(rows)
+    }
+
+    /*
+        The microcode ROM, one word per combination of opcode, funct,
+        state and Zero line.
+    */
+    pub fn rom(&self, isa: &Isa) -> Result<Vec<u32>, String> {
+        let (opcode_bits, funct_bits) = rom_fields(isa);
+        let mut rom = vec![0u32; 1 << (opcode_bits + funct_bits + 4)];
+        let word = |lines: ControlLines, next: State| (lines.word() as u32) << 3 | next as u32;
+        for opcode in 0..1u16 << opcode_bits {
+            for funct in 0..1u16 << funct_bits {
+                let ir = isa.layout(Format::R).place("op", opcode) | isa.layout(Format::R).place("funct", funct);
+                let encoding = isa.decode(ir).first().map(|encoding| (*encoding).clone());
+                for state in STATES.iter().cloned() {
+                    for zero in [false, true].iter().cloned() {
+                        let address = ((opcode as usize) << funct_bits | funct as usize) << 4 | (state as usize) << 1 | zero as usize;
+                        rom[address] = match (&encoding, state) {
+                            (Some(encoding), _) if self.states(encoding).contains(&state) =>
+                                word(self.lines(Some(encoding), state, zero)?, self.next_state(encoding, state)),
+                            (None, State::Fetch) => word(self.lines(None, State::Fetch, zero)?, State::Decode),
+                            (None, State::Decode) => word(self.lines(None, State::Decode, zero)?, State::Fetch),
+                            _ => 0,
+                        };
+                    }
+                }
+            }
+        }
+        Ok(rom)
+    }
+}
+
+/*
+    Widths of the opcode and funct that address the ROM.
+*/
+fn rom_fields(isa: &Isa) -> (u32, u32) {
+    let layout = isa.layout(Format::R);
+    (layout.field("op").width() as u32, layout.field("funct").width() as u32)
+}
+
+fn selects(rule: &Rule, encoding: Option<&Encoding>) -> bool {
+    let encoding = match encoding {
+        Some(encoding) => encoding,
+        None => return rule.selectors.iter().any(|s| s == "all"),
+    };
+    let format = match encoding.format {
+        Format::R => "rtype",
+        Format::J => "jtype",
+        Format::I => "itype",
+        Format::IWide => "iwide",
+    };
+    rule.selectors.iter().any(|s| s == "all" || s == format || *s == encoding.mnemonic)
+}
+
+fn set(lines: &mut ControlLines, name: &str, value: &str, mnemonic: &str, zero: bool) -> Result<(), String> {
+    let unrecognized = || Err(format!("Unrecognized value {} for {}", value, name));
+    match name {
+        "BranchOrJumpOrSequential" => lines.branch_or_jump_or_sequential = match value {
+            "Hold" => PcSource::Hold,
+            "Sequential" => PcSource::Sequential,
+            "Branch" => PcSource::Branch,
+            "Jump" => PcSource::Jump,
+            "BranchIfTaken" if zero == (mnemonic == "beq") => PcSource::Branch,
+            "BranchIfTaken" => PcSource::Hold,
+            _ => return unrecognized(),
+        },
+        "MemAddrFromPCOrALU" => lines.mem_addr_from_pc_or_alu = match value {
+            "PC" => MemAddr::PC,
+            "ALU" => MemAddr::ALU,
+            _ => return unrecognized(),
+        },
+        "MemReadOrWrite" => lines.mem_read_or_write = match value {
+            "Idle" => MemAccess::Idle,
+            "Read" => MemAccess::Read,
+            "Write" => MemAccess::Write,
+            _ => return unrecognized(),
+        },
+        "LoadIR" => lines.load_ir = match value {
+            "0" => false,
+            "1" => true,
+            _ => return unrecognized(),
+        },
+        "RegReadOrWrite" => lines.reg_read_or_write = match value {
+            "Read" => RegAccess::Read,
+            "Write" => RegAccess::Write,
+            _ => return unrecognized(),
+        },
+        "SecondOpComingFromImmediateOrReg2" => lines.second_op_from_immediate_or_reg2 = match value {
+            "Immediate" => SecondOp::Immediate,
+            "Reg2" => SecondOp::Reg2,
+            _ => return unrecognized(),
+        },
+        "WriteRegFromMemoryOrALU" => lines.write_reg_from_memory_or_alu = match value {
+            "Memory" => RegSource::Memory,
+            "ALU" => RegSource::ALU,
+            "PC" => RegSource::PC,
+            _ => return unrecognized(),
+        },
+        "ALUFunction" => lines.alu_function = match value {
+            "instruction" => alu_function(mnemonic).ok_or_else(|| format!("The ALU has no function for {}", mnemonic))?,
+            "Add" => AluFunction::Add,
+            "Sub" => AluFunction::Sub,
+            "And" => AluFunction::And,
+            "Or" => AluFunction::Or,
+            "Nor" => AluFunction::Nor,
+            "SLL" => AluFunction::SLL,
+            "SRL" => AluFunction::SRL,
+            "SRA" => AluFunction::SRA,
+            "LoadLower" => AluFunction::LoadLower,
+            "LoadUpper" => AluFunction::LoadUpper,
+            _ => return unrecognized(),
+        },
+        other => return Err(format!("Unrecognized control line {}", other)),
+    }
+    Ok(())
+}
+
+const COLUMNS: [&str; 14] = [
+    "Instruction", "Opcode", "Funct", "State", "Zero",
+    "BranchOrJumpOrSequential", "MemAddrFromPCOrALU", "MemReadOrWrite", "LoadIR", "RegReadOrWrite",
+    "SecondOpComingFromImmediateOrReg2", "WriteRegFromMemoryOrALU", "ALUFunction", "NextState",
+];
+
+fn cells(row: &Row) -> Vec<String> {
+    let lines = &row.lines;
+    vec![
+        row.mnemonic.clone(),
+        format!("{:X}", row.opcode),
+        format!("{:X}", row.funct),
+        format!("{:?}", row.state),
+        row.zero.map_or(String::from("x"), |zero| (zero as u8).to_string()),
+        format!("{:?}", lines.branch_or_jump_or_sequential),
+        format!("{:?}", lines.mem_addr_from_pc_or_alu),
+        format!("{:?}", lines.mem_read_or_write),
+        (lines.load_ir as u8).to_string(),
+        format!("{:?}", lines.reg_read_or_write),
+        format!("{:?}", lines.second_op_from_immediate_or_reg2),
+        format!("{:?}", lines.write_reg_from_memory_or_alu),
+        format!("{:?}", lines.alu_function),
+        format!("{:?}", row.next),
+    ]
+}
+
+pub fn csv(rows: &[Row]) -> String {
+    let mut text = COLUMNS.join(",") + "\n";
+    for row in rows.iter() {
+        text += &(cells(row).join(",") + "\n");
+    }
+    text
+}
+
+pub fn markdown(rows: &[Row]) -> String {
+    let mut text = format!("| {} |\n", COLUMNS.join(" | "));
+    text += &format!("|{}\n", COLUMNS.iter().map(|_| "---|").collect::<String>());
+    for row in rows.iter() {
+        text += &format!("| {} |\n", cells(row).join(" | "));
+    }
+    text
+}
+
+pub fn rom_mif(rom: &[u32], isa: &Isa) -> String {
+    let (opcode_bits, funct_bits) = rom_fields(isa);
+    let mut text = format!("\
+-- Microcode ROM: address opcode[{}:{}] funct[{}:4] state[3:1] zero[0],
+-- data control_word[18:3] next_state[2:0]
+DEPTH = {};                 -- The size of memory in words
+WIDTH = 19;                   -- The size of data in bits
+ADDRESS_RADIX = HEX;          -- The radix for address values
+DATA_RADIX = HEX;             -- The radix for data values
+CONTENT                       -- start of (address : data pairs)
+BEGIN\n", opcode_bits + funct_bits + 3, funct_bits + 4, funct_bits + 3, rom.len());
+    for (address, word) in rom.iter().enumerate() {
+        text += &format!("{:X} : {:X};\n", address, word);
+    }
+    text += "END;\n";
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::control::*;
+
+    #[test]
+    fn truth_table_splits_on_zero_only_for_branches() {
+        let rows = ControlSpec::default().truth_table(&Isa::default()).unwrap();
+        let text = csv(&rows);
+        assert!(text.contains("\nadd,0,0,Execute,x,Hold,PC,Idle,0,Read,Reg2,ALU,Add,Writeback\n"));
+        assert!(text.contains("\nbne,6,0,Execute,0,Branch,PC,Idle,0,Read,Reg2,ALU,Sub,Fetch\n"));
+        assert!(text.contains("\nbne,6,0,Execute,1,Hold,PC,Idle,0,Read,Reg2,ALU,Sub,Fetch\n"));
+        assert_eq!(rows.iter().filter(|row| row.zero.is_some()).count(), 4);
+        assert_eq!(markdown(&rows).lines().nth(2).unwrap(),
+            "| add | 0 | 0 | Fetch | x | Sequential | PC | Read | 1 | Read | Immediate | ALU | Add | Decode |");
+    }
+
+    #[test]
+    fn rom_addresses_control_inputs() {
+        let rom = ControlSpec::default().rom(&Isa::default()).unwrap();
+        // lw (opcode 3) in memory, zero clear
+        assert_eq!(rom[3 << 7 | 3 << 1], (0x2940 << 3) | State::Writeback as u32);
+        // fetch is the same for an unused opcode
+        assert_eq!(rom[0xC << 7 | 5 << 4], rom[3 << 7]);
+        assert_eq!(rom[0xC << 7 | 2 << 1], 0);
+        let mif = rom_mif(&rom, &Isa::default());
+        assert!(mif.starts_with("-- Microcode ROM: address opcode[10:7] funct[6:4] state[3:1] zero[0],\n"));
+        assert!(mif.contains("\nDEPTH = 2048; "));
+        assert!(mif.contains("\n186 : 14A04;\n"));
+    }
+
+    #[test]
+    fn rom_follows_the_rtype_layout() {
+        let isa = Isa::load("[rtype]\nadd = 0\nsub = 1\n[itype]\nlw = 3\n[formats]\nrtype = op[15:12] r1[11:9] r2[8:6] r3[5:2] funct[1:0]\n");
+        let rom = ControlSpec::default().rom(&isa).unwrap();
+        assert_eq!(rom.len(), 1 << 10);
+        // lw in memory, its opcode above a 2-bit funct
+        assert_eq!(rom[3 << 6 | 3 << 1], (0x2940 << 3) | State::Writeback as u32);
+        assert!(rom_mif(&rom, &isa).starts_with("-- Microcode ROM: address opcode[9:6] funct[5:4] state[3:1] zero[0],\n"));
+    }
+
+    #[test]
+    fn truth_table_needs_alu_functions() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n");
+        assert_eq!(ControlSpec::default().truth_table(&isa).err(), Some(String::from("The ALU has no function for xor")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unrecognized value Later for MemReadOrWrite on line 3")]
+    fn spec_values_are_checked() {
+        ControlSpec::parse("[memory: lw]\nMemAddrFromPCOrALU = ALU\nMemReadOrWrite = Later\n");
+    }
+}
@@ -0,0 +1,336 @@
+/*
+    Interactive debugger on top of the simulator. Commands:
+
+        step [n]            s   run n instructions, 1 by default
+        next                n   step, running over calls made with jal or jali
+        continue            c   run until a breakpoint, a watch or the end
+        break where         b   stop before the instruction at where
+        delete where            remove a breakpoint
+        watch where         w   stop when the memory word at where changes
+        registers           r   print registers and ALU flags
+        memory where [n]    m   print n memory words, 8 by default
+        list                l   disassemble around the PC
+        quit                q
+
+    Places are labels from the program's symbol table or word addresses,
+    as shown in listings, in decimal or 0x hex. Each stop shows why it
+    stopped and the disassembly around the PC; a fault such as an illegal
+    instruction stops on the faulting instruction.
+
+    next runs a call until the PC first reaches the instruction after it,
+    without tracking call depth, so in a recursive function it stops when
+    a deeper call returns there.
+*/
+
+use disasm::decode;
+use disasm::target;
+use disasm::with_label;
+use simulator::Machine;
+
+const LIST_BEFORE: u16 = 3;
+const LIST_AFTER: u16 = 4;
+const MEMORY_WORDS_SHOWN: u16 = 8;
+
+pub struct Debugger {
+    pub machine: Machine,
+    symbols: Vec<(String, u16)>,
+    breakpoints: Vec<u16>,
+    watches: Vec<(u16, u16)>, // word address and the value last seen
+    max_steps: u64, // per command, so runaway programs return to the prompt
+}
+
+impl Debugger {
+    pub fn new(machine: Machine, symbols: Vec<(String, u16)>, max_steps: u64) -> Debugger {
+        Debugger { machine, symbols, breakpoints: Vec::new(), watches: Vec::new(), max_steps }
+    }
+
+    /*
+        Runs one command line, returning what to print and whether the
+        session is over.
+    */
+    pub fn command(&mut self, line: &str) -> (String, bool) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words.as_slice() {
+            [] => Ok(String::new()),
+            ["step"] | ["s"] => Ok(self.step(1)),
+            ["step", count] | ["s", count] => match count.parse::<u64>() {
+                Ok(count) => Ok(self.step(count)),
+                Err(_) => Err(format!("Expected a number of steps, got {}", count)),
+            },
+            ["next"] | ["n"] => Ok(self.next()),
+            ["continue"] | ["c"] => Ok(self.resume(None)),
+            ["break", place] | ["b", place] => self.resolve(place).map(|addr| {
+                if !self.breakpoints.contains(&addr) {
+                    self.breakpoints.push(addr);
+                }
+                format!("Breakpoint at {}\n", self.describe(addr))
+            }),
+            ["delete", place] => self.resolve(place).and_then(|addr| {
+                match self.breakpoints.iter().position(|b| *b == addr) {
+                    Some(i) => {
+                        self.breakpoints.remove(i);
+                        Ok(format!("Deleted the breakpoint at {}\n", self.describe(addr)))
+                    },
+                    None => Err(format!("No breakpoint at {}", self.describe(addr))),
+                }
+            }),
+            ["watch", place] | ["w", place] => self.resolve(place).and_then(|addr| {
+                match self.machine.memory.get(addr as usize) {
+                    Some(value) => {
+                        let value = *value;
+                        self.watches.push((addr, value));
+                        Ok(format!("Watching {} = {:#06X}\n", self.describe(addr), value))
+                    },
+                    None => Err(format!("{:#06X} is outside memory", addr)),
+                }
+            }),
+            ["registers"] | ["r"] => Ok(self.machine.dump_registers()),
+            ["memory", place] | ["m", place] => self.resolve(place).map(|addr| self.memory(addr, MEMORY_WORDS_SHOWN)),
+            ["memory", place, count] | ["m", place, count] => self.resolve(place).and_then(|addr| {
+                match count.parse::<u16>() {
+                    Ok(count) => Ok(self.memory(addr, count)),
+                    Err(_) => Err(format!("Expected a number of words, got {}", count)),
+                }
+            }),
+            ["list"] | ["l"] => Ok(self.list()),
+            ["quit"] | ["q"] => return (String::new(), true),
+            _ => Err(format!("Unrecognized command {}", line.trim())),
+        };
+        match result {
+            Ok(text) => (text, false),
+            Err(msg) => (msg + "\n", false),
+        }
+    }
+
+    fn step(&mut self, count: u64) -> String {
+        for _ in 0..count {
+            if self.machine.halted {
+                break;
+            }
+            if let Err(fault) = self.machine.step() {
+                return fault + "\n" + &self.list();
+            }
+            if let Some(reason) = self.watch_hit() {
+                return reason + &self.list();
+            }
+        }
+        self.stopped()
+    }
+
+    /*
+        Steps, or for a call, runs until the PC reaches the next
+        instruction, which a recursive call reaches before the outer call
+        returns.
+    */
+    fn next(&mut self) -> String {
+        let pc = self.machine.pc;
+        let word = self.machine.memory.get(pc as usize).cloned().unwrap_or(0);
+        let is_call = self.machine.isa()
+            .decode(word)
+            .first()
+            .is_some_and(|encoding| encoding.mnemonic == "jal" || encoding.mnemonic == "jali");
+        if is_call {
+            self.resume(Some(pc.wrapping_add(1)))
+        } else {
+            self.step(1)
+        }
+    }
+
+    /*
+        Runs until a breakpoint, a changed watch, the program's end, or
+        until the PC reaches until.
+    */
+    fn resume(&mut self, until: Option<u16>) -> String {
+        let mut steps = 0;
+        while !self.machine.halted {
+            if steps == self.max_steps {
+                return format!("Still running after {} steps\n", steps) + &self.list();
+            }
+            if let Err(fault) = self.machine.step() {
+                return fault + "\n" + &self.list();
+            }
+            steps += 1;
+            if let Some(reason) = self.watch_hit() {
+                return reason + &self.list();
+            }
+            let pc = self.machine.pc;
+            if Some(pc) == until {
+                break;
+            }
+            if self.breakpoints.contains(&pc) && !self.machine.halted {
+                return format!("Breakpoint at {}\n", self.describe(pc)) + &self.list();
+            }
+        }
+        self.stopped()
+    }
+
+    fn stopped(&self) -> String {
+        if self.machine.halted {
+            format!("Program stopped at {} after {} steps\n", self.describe(self.machine.pc), self.machine.steps)
+        } else {
+            self.list()
+        }
+    }
+
+    fn watch_hit(&mut self) -> Option<String> {
+        for i in 0..self.watches.len() {
+            let (addr, old) = self.watches[i];
+            let new = self.machine.memory[addr as usize];
+            if new != old {
+                self.watches[i].1 = new;
+                return Some(format!("Watch {} changed from {:#06X} to {:#06X}\n", self.describe(addr), old, new));
+            }
+        }
+        None
+    }
+
+    fn resolve(&self, place: &str) -> Result<u16, String> {
+        if let Some((_, addr)) = self.symbols.iter().find(|(name, _)| name == place) {
+            return Ok(*addr);
+        }
+        let parsed = if let Some(hex) = place.strip_prefix("0x") {
+            u16::from_str_radix(hex, 16)
+        } else {
+            place.parse::<u16>()
+        };
+        parsed.map_err(|_| format!("{} is neither a label nor an address", place))
+    }
+
+    fn label(&self, addr: u16) -> Option<&str> {
+        self.symbols.iter().find(|(_, a)| *a == addr).map(|(name, _)| name.as_str())
+    }
+
+    fn describe(&self, addr: u16) -> String {
+        match self.label(addr) {
+            Some(name) => format!("{} ({:#06X})", name, addr),
+            None => format!("{:#06X}", addr),
+        }
+    }
+
+    fn memory(&self, addr: u16, count: u16) -> String {
+        let mut text = String::new();
+        for addr in (addr as u32..addr as u32 + count as u32).take_while(|a| (*a as usize) < self.machine.memory.len()) {
+            let value = self.machine.memory[addr as usize];
+            let label = self.label(addr as u16).map_or(String::new(), |name| format!("  <{}>", name));
+            text += &format!("{:04X}: {:04X} {:>6}{}\n", addr, value, value as i16, label);
+        }
+        text
+    }
+
+    /*
+        Disassembly of the loaded words around the PC, with labels from the
+        symbol table.
+    */
+    pub fn list(&self) -> String {
+        let pc = self.machine.pc;
+        let mut text = String::new();
+        let first = pc.saturating_sub(LIST_BEFORE);
+        for addr in first..=pc.saturating_add(LIST_AFTER) {
+            if addr as usize >= self.machine.memory.len() || !self.machine.loaded[addr as usize] {
+                continue;
+            }
+            if let Some(name) = self.label(addr) {
+                text += &format!("{}:\n", name);
+            }
+            let word = self.machine.memory[addr as usize];
+            let isa = self.machine.isa();
+            let instr = decode(word, isa);
+            let instr = match target(addr, &instr, isa).and_then(|target| self.label(target)) {
+                Some(label) => with_label(&instr, label),
+                None => instr,
+            };
+            let marker = if addr == pc { "=>" } else { "  " };
+            text += &format!("{} {:04X}: {:04X}  {}\n", marker, addr, word, instr);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, assembler::image, assembler::Options, debugger::*, isa::Isa};
+
+    fn debugger(source: &str) -> Debugger {
+        let isa = Isa::default();
+        let image = image(&assemble(source, &Options::default()), &isa);
+        let mut machine = Machine::new(&isa);
+        machine.load(&image);
+        Debugger::new(machine, image.symbols.clone(), 10000)
+    }
+
+    static PROGRAM: &str = "
+        count:
+        .word 0
+        main:
+        lli $a0, 3
+        loop:
+        jal bump
+        addi $a0, $a0, -1
+        bne $a0, $0, loop
+        done:
+        beq $0, $0, done
+        bump:
+        lli $v1, %lo(count)
+        lw $a1, 0($v1)
+        addi $a1, $a1, 1
+        sw $a1, 0($v1)
+        jmpi 0($ra)
+    ";
+
+    #[test]
+    fn step_and_list() {
+        let mut debugger = debugger(PROGRAM);
+        assert_eq!(debugger.command("s 2").0, "   0003: 793F  addi $a0, $a0, -1
+   0004: 683D  bne $a0, $0, loop
+done:
+   0005: 503F  beq $0, $0, done
+bump:
+=> 0006: A600  lli $v1, 0
+   0007: 3AC0  lw $a1, 0($v1)
+   0008: 7B41  addi $a1, $a1, 1
+   0009: 4AC0  sw $a1, 0($v1)
+   000A: 8E00  jmpi 0($ra)
+");
+        assert!(debugger.list().contains("bump:\n=> 0006"));
+    }
+
+    #[test]
+    fn next_runs_over_calls() {
+        let mut debugger = debugger(PROGRAM);
+        debugger.command("step");
+        debugger.command("next");
+        assert_eq!(debugger.machine.pc, 3);
+        assert_eq!(debugger.machine.memory[0], 1);
+    }
+
+    #[test]
+    fn breakpoints_and_watches() {
+        let mut debugger = debugger(PROGRAM);
+        assert_eq!(debugger.command("b loop").0, "Breakpoint at loop (0x0002)\n");
+        assert!(debugger.command("c").0.starts_with("Breakpoint at loop (0x0002)\n"));
+        assert_eq!(debugger.command("w count").0, "Watching count (0x0000) = 0x0000\n");
+        assert!(debugger.command("c").0.starts_with("Watch count (0x0000) changed from 0x0000 to 0x0001\n"));
+        assert_eq!(debugger.command("delete loop").0, "Deleted the breakpoint at loop (0x0002)\n");
+        debugger.command("c");
+        debugger.command("c");
+        assert_eq!(debugger.command("c").0, "Program stopped at done (0x0005) after 26 steps\n");
+        assert_eq!(debugger.command("m count 1").0, "0000: 0003      3  <count>\n");
+        assert_eq!(debugger.command("b nowhere").0, "nowhere is neither a label nor an address\n");
+        assert_eq!(debugger.command("q"), (String::new(), true));
+    }
+
+    #[test]
+    fn faults_stop_on_the_instruction() {
+        let mut debugger = debugger("main:\nli $a0, 0x8000\nlw $v0, 0($a0)\naddi $v0, $v0, 1\n");
+        assert_eq!(debugger.command("c").0, "Load from 0x8000 at 0x0002 is outside memory
+main:
+   0000: A800  lli $a0, 0
+   0001: B880  lui $a0, 128
+=> 0002: 3500  lw $v0, 0($a0)
+   0003: 7481  addi $v0, $v0, 1
+");
+        assert_eq!((debugger.machine.pc, debugger.machine.steps), (2, 2));
+        assert!(debugger.command("s").0.starts_with("Load from 0x8000 at 0x0002 is outside memory\n"));
+        assert!(debugger.command("s 5").0.starts_with("Load from"));
+    }
+}
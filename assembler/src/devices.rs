@@ -0,0 +1,88 @@
+/*
+    Memory-mapped devices for the simulator. A device is attached at a
+    byte address and answers the `lw` and `sw` instructions that use it in
+    place of memory. The console devices move one character per word: the
+    input device reads the next byte, or 0xFFFF once the input is used up,
+    and the output device writes the low byte of the stored word. A
+    device that fails returns why, which stops the program.
+*/
+
+use std::io::Read;
+use std::io::Write;
+
+pub const INPUT_ADDRESS: u16 = 0xFF04;
+pub const OUTPUT_ADDRESS: u16 = 0xFF08;
+
+pub trait Device {
+    fn name(&self) -> &'static str;
+    fn read(&mut self) -> Result<u16, String>;
+    fn write(&mut self, value: u16) -> Result<(), String>;
+}
+
+pub struct ConsoleInput {
+    input: Box<dyn Read>,
+}
+
+impl ConsoleInput {
+    pub fn new(input: Box<dyn Read>) -> ConsoleInput {
+        ConsoleInput { input }
+    }
+}
+
+impl Device for ConsoleInput {
+    fn name(&self) -> &'static str {
+        "input"
+    }
+
+    fn read(&mut self) -> Result<u16, String> {
+        let mut byte = [0u8; 1];
+        match self.input.read(&mut byte) {
+            Ok(1) => Ok(byte[0] as u16),
+            Ok(_) => Ok(0xFFFF),
+            Err(err) => Err(format!("Unable to read from the input device: {}", err)),
+        }
+    }
+
+    fn write(&mut self, _value: u16) -> Result<(), String> {
+        Err(String::from("The input device cannot be written"))
+    }
+}
+
+pub struct ConsoleOutput {
+    output: Box<dyn Write>,
+}
+
+impl ConsoleOutput {
+    pub fn new(output: Box<dyn Write>) -> ConsoleOutput {
+        ConsoleOutput { output }
+    }
+}
+
+impl Device for ConsoleOutput {
+    fn name(&self) -> &'static str {
+        "output"
+    }
+
+    fn read(&mut self) -> Result<u16, String> {
+        Err(String::from("The output device cannot be read"))
+    }
+
+    fn write(&mut self, value: u16) -> Result<(), String> {
+        // flush each character so a terminal shows output as it is written
+        self.output.write_all(&[value as u8])
+            .and_then(|_| self.output.flush())
+            .map_err(|err| format!("Unable to write to the output device: {}", err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::devices::*;
+
+    #[test]
+    fn input_ends_with_all_ones() {
+        let mut input = ConsoleInput::new(Box::new(&b"hi"[..]));
+        assert_eq!((input.read(), input.read(), input.read()), (Ok(0x68), Ok(0x69), Ok(0xFFFF)));
+        assert_eq!(input.write(0), Err(String::from("The input device cannot be written")));
+    }
+}
@@ -0,0 +1,210 @@
+/*
+    Turns a memory image back into assembly that the assembler accepts.
+    Words are decoded against the ISA's opcode tables; those that decode
+    to nothing the assembler can write, or whose encoding would not come
+    back the same, are kept as `.word`. Every r-type instruction
+    assembles, while other formats only assemble the mnemonics of the
+    default tables. Branch and jump targets within the image get a label,
+    the image's own symbol if it has one there.
+*/
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+
+use assembler::instr_to_words;
+use assembler::parse_itype;
+use assembler::parse_jtype;
+use assembler::parse_register;
+use assembler::parse_rtype;
+use ir::Directive;
+use ir::IRInstruction;
+use ir::IType;
+use ir::Register;
+use isa::Format;
+use isa::Isa;
+use isa::{IWIDE_MNEMONICS, ITYPE_MNEMONICS, JTYPE_MNEMONICS};
+use output::Image;
+
+pub fn disassemble(image: &Image, isa: &Isa) -> String {
+    let decoded: Vec<(u16, u16, IRInstruction)> = image.words
+        .iter()
+        .map(|(addr, word)| (*addr, *word, decode(*word, isa)))
+        .collect();
+
+    let addresses: BTreeSet<u16> = image.words.iter().map(|(addr, _)| *addr).collect();
+    let in_image = |addr: u16| addresses.contains(&addr);
+    let mut labels: BTreeMap<u16, String> = BTreeMap::new();
+    for (name, addr) in image.symbols.iter().rev() {
+        // the first symbol at an address names it
+        if in_image(*addr) {
+            labels.insert(*addr, name.clone());
+        }
+    }
+    for (addr, _, instr) in decoded.iter() {
+        match target(*addr, instr, isa) {
+            Some(target) if in_image(target) && !labels.contains_key(&target) => {
+                labels.insert(target, format!("L_{:04X}", target));
+            },
+            _ => (),
+        }
+    }
+
+    let mut text = String::new();
+    let mut next: u32 = 0;
+    for (addr, word, instr) in decoded.into_iter() {
+        if addr as u32 != next {
+            text += &format!(".org {:#X}\n", addr);
+        }
+        next = addr as u32 + 1;
+        if let Some(label) = labels.get(&addr) {
+            text += &format!("{}:\n", label);
+        }
+        let instr = match target(addr, &instr, isa).and_then(|target| labels.get(&target)) {
+            Some(label) => with_label(&instr, label),
+            None => instr,
+        };
+        text += &format!("    {:<24}# {:04X}: {:04X}\n", instr.to_string(), addr, word);
+    }
+    text
+}
+
+/*
+    The instruction a word encodes, or the word itself as data.
+*/
+pub fn decode(word: u16, isa: &Isa) -> IRInstruction {
+    let data = IRInstruction::Directive(Directive::Word(word));
+    let encoding = match isa.decode(word).first() {
+        Some(encoding) => *encoding,
+        None => return data,
+    };
+    let layout = isa.layout(encoding.format);
+    let mnemonic = encoding.mnemonic.as_str();
+    let register = |field: &str| register_name(isa, layout.field(field).extract(word));
+    let signed = |field: &str| {
+        let field = layout.field(field);
+        let shift = 16 - field.width();
+        ((field.extract(word) << shift) as i16) >> shift
+    };
+    let instr = match encoding.format {
+        Format::R => match (register("r1"), register("r2"), register("r3")) {
+            (Some(r1), Some(r2), Some(r3)) => IRInstruction::RType(parse_rtype(mnemonic), r1, r2, r3),
+            _ => return data,
+        },
+        Format::J if JTYPE_MNEMONICS.contains(&mnemonic) =>
+            IRInstruction::JTypeAddr(parse_jtype(mnemonic), layout.field("address").extract(word)),
+        Format::I if ITYPE_MNEMONICS.contains(&mnemonic) => match (register("r1"), register("r2")) {
+            (Some(r1), Some(r2)) => IRInstruction::IType(parse_itype(mnemonic), r1, r2, signed("imm") as i8),
+            _ => return data,
+        },
+        Format::IWide if IWIDE_MNEMONICS.contains(&mnemonic) => match register("r1") {
+            Some(r1) => IRInstruction::ITypeWide(parse_itype(mnemonic), r1, signed("imm")),
+            None => return data,
+        },
+        _ => return data,
+    };
+    // fields the syntax cannot express, such as an lli byte past 255, stay data
+    if !representable(&instr) || instr_to_words(&instr, isa) != vec![word] {
+        return data;
+    }
+    instr
+}
+
+fn register_name(isa: &Isa, number: u16) -> Option<Register> {
+    isa.registers
+        .iter()
+        .find(|(_, n)| *n == number)
+        .map(|(name, _)| parse_register(name))
+}
+
+fn representable(instr: &IRInstruction) -> bool {
+    match instr {
+        IRInstruction::ITypeWide(IType::LLI, _, imm) | IRInstruction::ITypeWide(IType::LUI, _, imm) =>
+            *imm >= i8::MIN as i16 && *imm <= u8::MAX as i16,
+        _ => true,
+    }
+}
+
+/*
+    Word address a branch or j-type jump at addr transfers to.
+*/
+pub fn target(addr: u16, instr: &IRInstruction, isa: &Isa) -> Option<u16> {
+    match instr {
+        IRInstruction::IType(IType::BEQ, _, _, offset) | IRInstruction::IType(IType::BNE, _, _, offset) =>
+            Some(addr.wrapping_add(1).wrapping_add(*offset as i16 as u16)),
+        IRInstruction::JTypeAddr(_, field) => {
+            let region = !(isa.layout(Format::J).field("address").mask() >> isa.layout(Format::J).field("address").lo);
+            Some((addr.wrapping_add(1) & region) | field)
+        },
+        _ => None,
+    }
+}
+
+pub fn with_label(instr: &IRInstruction, label: &str) -> IRInstruction {
+    match instr {
+        IRInstruction::IType(itype, r1, r2, _) =>
+            IRInstruction::ITypeLabel(itype.clone(), r1.clone(), r2.clone(), label.to_string()),
+        IRInstruction::JTypeAddr(jtype, _) => IRInstruction::JTypeLabel(jtype.clone(), label.to_string()),
+        _ => instr.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, assembler::image, assembler::Options, disasm::*, isa::Isa};
+
+    #[test]
+    fn disassemble_with_labels() {
+        let isa = Isa::default();
+        let source = "main:\nlli $v0, -3\nloop:\naddi $v0, $v0, 1\nbne $v0, $0, loop\njal print\nsw $v0, -2($sp)\n.word 0xF123\nprint:\njmpi 0($ra)\n";
+        let program = assemble(source, &Options::default());
+        assert_eq!(disassemble(&image(&program, &isa), &isa), "\
+main:
+    lli $v0, -3             # 0000: A5FD
+loop:
+    addi $v0, $v0, 1        # 0001: 7481
+    bne $v0, $0, loop       # 0002: 643E
+    jal print               # 0003: 2006
+    sw $v0, -2($sp)         # 0004: 45BE
+    .word 0xF123            # 0005: F123
+print:
+    jmpi 0($ra)             # 0006: 8E00
+");
+    }
+
+    #[test]
+    fn disassembly_reassembles() {
+        let isa = Isa::default();
+        let words: Vec<(u16, u16)> = vec![(0, 0x5002), (1, 0x1004), (2, 0xA500), (8, 0x0528), (9, 0x2000), (0x20, 0x1000)];
+        let image = Image::from_words(words.clone(), &Default::default());
+        let text = disassemble(&image, &isa);
+        assert!(text.contains(".word 0xA500"));
+        assert!(text.contains(".org 0x8\n"));
+        let program = assemble(&text, &Options::default());
+        assert_eq!(crate::assembler::image(&program, &isa).words, words);
+    }
+
+    #[test]
+    fn decode_rtypes_the_isa_declares() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n[registers]\n$0 = 0\n$t0 = 1\n");
+        assert_eq!(decode(0x020D, &isa).to_string(), "xor $t0, $0, $t0");
+    }
+
+    #[test]
+    fn every_decoded_word_reassembles() {
+        let isa = Isa::default();
+        for word in 0..=0xFFFFu16 {
+            let instr = decode(word, &isa);
+            assert_eq!(instr_to_words(&crate::assembler::parse_line(&instr.to_string()), &isa), vec![word], "{}", instr);
+        }
+    }
+
+    #[test]
+    fn disassembled_program_reassembles() {
+        // a spread of encodings, so branches and jumps land both inside and outside the image
+        let isa = Isa::default();
+        let words: Vec<(u16, u16)> = (0..0x2000u16).map(|addr| (addr, addr.wrapping_mul(0x1F3D))).collect();
+        let text = disassemble(&Image::from_words(words.clone(), &Default::default()), &isa);
+        let program = assemble(&text, &Options::default());
+        assert_eq!(crate::assembler::image(&program, &isa).words, words);
+    }
+}
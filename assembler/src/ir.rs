@@ -8,7 +8,7 @@ pub enum Register {
     A1,
     Sp,
     Ra,
-    None,
+    Named(String), // a register only an ISA description declares
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -18,6 +18,9 @@ pub enum Directive {
     Byte(u8), // store byte and increment address counter
     Word(u16), // store word and increment address counter
     Asciiz(String), // store string and increment address counter
+    Globl(String), // export symbol to other files
+    Extern(String), // symbol defined in another file
+    Section(String), // switch to the named section's address counter
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -30,6 +33,7 @@ pub enum RType {
     SLL,
     SRL,
     SRA,
+    Named(String), // an r-type instruction only an ISA description declares
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -51,6 +55,12 @@ pub enum IType {
     LUI,
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum Half {
+    Hi, // upper byte of a label's byte address
+    Lo, // lower byte of a label's byte address
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Pseudo {
     LI(Register, i16), // Pseudo-instr for loading 16 bit immediates
@@ -64,6 +74,9 @@ pub enum IRInstruction {
     JTypeLabel(JType, String),
     JTypeAddr(JType, u16),
     IType(IType, Register, Register, i8),
+    ITypeLabel(IType, Register, Register, String), // branch to a label
+    ITypeWide(IType, Register, i16), // one register and a 9-bit immediate
+    ITypeWideLabel(IType, Register, Half, String), // lli/lui of %lo/%hi of a label
     Pseudo(Pseudo),
     BlankLine,
 }
@@ -80,7 +93,7 @@ impl core::fmt::Display for Register {
             A1 => write!(f, "$a1"),
             Sp => write!(f, "$sp"),
             Ra => write!(f, "$ra"),
-            None => write!(f, "None"),
+            Named(name) => write!(f, "{}", name),
         }
     }
 }
@@ -89,11 +102,15 @@ impl core::fmt::Display for Directive {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         use self::Directive::*;
         match self {
-            Org(addr) => write!(f, ".org {}", addr),
+            Org(addr) => write!(f, ".org {:#X}", addr),
             Space(space) => write!(f, ".space {}", space),
             Byte(b) => write!(f, ".byte {}", b),
-            Word(w) => write!(f, ".word {}", w),
-            Asciiz(s) => write!(f, ".asciiz {}", s),
+            Word(w) => write!(f, ".word {:#X}", w),
+            Asciiz(s) => write!(f, ".asciiz \"{}\"", s),
+            Globl(s) => write!(f, ".globl {}", s),
+            Extern(s) => write!(f, ".extern {}", s),
+            Section(s) if s == ".text" || s == ".data" || s == ".bss" => write!(f, "{}", s),
+            Section(s) => write!(f, ".section {}", s),
         }
     }
 }
@@ -110,6 +127,7 @@ impl core::fmt::Display for RType {
             SLL => write!(f, "sll"),
             SRL => write!(f, "srl"),
             SRA => write!(f, "sra"),
+            Named(name) => write!(f, "{}", name),
         }
     }
 }
@@ -141,29 +159,52 @@ impl core::fmt::Display for IType {
     }
 }
 
+impl core::fmt::Display for Half {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        use self::Half::*;
+        match self {
+            Hi => write!(f, "%hi"),
+            Lo => write!(f, "%lo"),
+        }
+    }
+}
+
 impl core::fmt::Display for Pseudo {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         use self::Pseudo::*;
         match self {
-            LI(reg, im) => write!(f, "{}, {}", reg, im),
+            LI(reg, im) => write!(f, "li {}, {}", reg, im),
         }
     }
 }
 
+/*
+    Prints instructions in the syntax parse_line reads, so that the source
+    lines shown in MIF comments and disassemblies assemble again.
+*/
 impl core::fmt::Display for IRInstruction {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         use self::IRInstruction::*;
         match self {
             Directive(dir) => write!(f, "{}", dir),
-            Label(s) => write!(f, "{}", s),
+            Label(s) => write!(f, "{}:", s),
             RType(rtype, reg1, reg2, reg3) => 
-                write!(f, "{}, {}, {}, {}", rtype, reg1, reg2, reg3),
-            JTypeLabel(jtype, s) => write!(f, "{}, {}", jtype, s), 
-            JTypeAddr(jtype, addr) => write!(f, "{}, {}", jtype, addr),
+                write!(f, "{} {}, {}, {}", rtype, reg1, reg2, reg3),
+            JTypeLabel(jtype, s) => write!(f, "{} {}", jtype, s), 
+            JTypeAddr(jtype, addr) => write!(f, "{} {:#X}", jtype, addr),
+            IType(itype, reg1, reg2, im) if *itype == self::IType::LW || *itype == self::IType::SW
+                => write!(f, "{} {}, {}({})", itype, reg1, im, reg2),
             IType(itype, reg1, reg2, im) 
-                => write!(f, "{}, {}, {}, {}", itype, reg1, reg2, im),
+                => write!(f, "{} {}, {}, {}", itype, reg1, reg2, im),
+            ITypeLabel(itype, reg1, reg2, s)
+                => write!(f, "{} {}, {}, {}", itype, reg1, reg2, s),
+            ITypeWide(itype, reg, im) if *itype == self::IType::JmpI || *itype == self::IType::JalI
+                => write!(f, "{} {}({})", itype, im, reg),
+            ITypeWide(itype, reg, im) => write!(f, "{} {}, {}", itype, reg, im),
+            ITypeWideLabel(itype, reg, half, s)
+                => write!(f, "{} {}, {}({})", itype, reg, half, s),
             Pseudo(pseudo) => write!(f, "{}", pseudo),
-            BlankLine => write!(f, "BlankLine"),
+            BlankLine => Ok(()),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,595 @@
+/*
+    Description of the instruction set the assembler targets. The default
+    tables match the hardware built this semester; other variants can be
+    loaded at runtime from a description file of the form:
+
+        # comment
+        [rtype]
+        opcode = 0      # shared opcode of every r-type instruction
+        add = 0         # funct code
+        new xor = 5     # an instruction the default tables lack
+        [jtype]
+        jmp = 0b0001    # opcode
+        [itype]
+        lw = 0x3        # opcode
+        [iwide]
+        jmpi = 8        # opcode
+        [registers]
+        $sp = 6         # register number
+        [formats]
+        itype = op[15:12] r1[11:9] r2[8:6] imm[5:0]
+
+    Instructions or registers left out of a description are not part of
+    that ISA and are rejected when assembling. A description may name
+    registers and r-type functs of its own, which assemble like the
+    default ones; as many registers fit as the register fields allow.
+    Instructions missing from the default tables must be declared with
+    `new`, so that a misspelled mnemonic is reported rather than loaded;
+    they may be listed in any section so that the table can describe the
+    hardware, though only new r-type ones assemble. A description without a
+    [registers] section keeps the default register numbering, and formats
+    left out of [formats] keep their default layout.
+*/
+
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    R,
+    J,
+    I,
+    IWide, // i-type with one register and a wider immediate
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Encoding {
+    pub mnemonic: String,
+    pub format: Format,
+    pub opcode: u16,
+    pub funct: u16, // only meaningful for r-type instructions
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    pub name: String,
+    pub hi: u16,
+    pub lo: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub format: Format,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Isa {
+    pub instructions: Vec<Encoding>,
+    pub registers: Vec<(String, u16)>,
+    pub layouts: Vec<Layout>,
+}
+
+/*
+    Result of decoding every 16-bit word against an ISA's instruction table.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct CheckReport {
+    pub conflicts: Vec<Conflict>, // words claimed by more than one instruction
+    pub unused: Vec<Unused>, // words no instruction decodes
+    pub field_overlaps: Vec<(Format, String, String)>, // fields sharing bits
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub mnemonics: Vec<String>,
+    pub words: u32,
+    pub first: u16,
+    pub last: u16,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unused {
+    pub opcode: u16,
+    pub words: u32,
+}
+
+// default tables, grouped by the format of their operands
+pub const RTYPE_MNEMONICS: [&str; 8] = ["add", "sub", "and", "or", "nor", "sll", "srl", "sra"];
+pub const JTYPE_MNEMONICS: [&str; 2] = ["jmp", "jal"];
+pub const ITYPE_MNEMONICS: [&str; 5] = ["lw", "sw", "beq", "bne", "addi"];
+pub const IWIDE_MNEMONICS: [&str; 4] = ["jmpi", "jali", "lli", "lui"];
+// in the principles of operation, though no default table encodes them
+const OTHER_ITYPE_MNEMONICS: [&str; 1] = ["subi"];
+const OTHER_IWIDE_MNEMONICS: [&str; 1] = ["li"];
+const REGISTER_NAMES: [&str; 8] = ["$0", "$at", "$v0", "$v1", "$a0", "$a1", "$sp", "$ra"];
+const DEFAULT_LAYOUTS: [(Format, &str); 4] = [
+    (Format::R, "op[15:12] r1[11:9] r2[8:6] r3[5:3] funct[2:0]"),
+    (Format::J, "op[15:12] address[11:0]"),
+    (Format::I, "op[15:12] r1[11:9] r2[8:6] imm[5:0]"),
+    (Format::IWide, "op[15:12] r1[11:9] imm[8:0]"),
+];
+
+impl Default for Isa {
+    fn default() -> Isa {
+        let mut instructions = Vec::new();
+        for (funct, mnemonic) in RTYPE_MNEMONICS.iter().enumerate() {
+            instructions.push(Encoding {
+                mnemonic: mnemonic.to_string(),
+                format: Format::R,
+                opcode: 0,
+                funct: funct as u16,
+            });
+        }
+        for (i, mnemonic) in JTYPE_MNEMONICS.iter().enumerate() {
+            instructions.push(Encoding {
+                mnemonic: mnemonic.to_string(),
+                format: Format::J,
+                opcode: 1 + i as u16,
+                funct: 0,
+            });
+        }
+        for (i, mnemonic) in ITYPE_MNEMONICS.iter().enumerate() {
+            instructions.push(Encoding {
+                mnemonic: mnemonic.to_string(),
+                format: Format::I,
+                opcode: 3 + i as u16,
+                funct: 0,
+            });
+        }
+        for (i, mnemonic) in IWIDE_MNEMONICS.iter().enumerate() {
+            instructions.push(Encoding {
+                mnemonic: mnemonic.to_string(),
+                format: Format::IWide,
+                opcode: 8 + i as u16,
+                funct: 0,
+            });
+        }
+        let registers = REGISTER_NAMES
+            .iter()
+            .enumerate()
+            .map(|(i, name)| (name.to_string(), i as u16))
+            .collect();
+        let layouts = DEFAULT_LAYOUTS
+            .iter()
+            .map(|(format, fields)| parse_layout(*format, fields, 0))
+            .collect();
+        Isa { instructions, registers, layouts }
+    }
+}
+
+impl Field {
+    pub fn width(&self) -> u16 {
+        self.hi - self.lo + 1
+    }
+
+    pub fn mask(&self) -> u16 {
+        (((1u32 << self.width()) - 1) << self.lo) as u16
+    }
+
+    pub fn extract(&self, word: u16) -> u16 {
+        ((word as u32 >> self.lo) & ((1 << self.width()) - 1)) as u16
+    }
+}
+
+impl Layout {
+    pub fn field(&self, name: &str) -> &Field {
+        self.fields
+            .iter()
+            .find(|f| f.name == name)
+            .unwrap_or_else(|| panic!("{:?}-type format has no {} field", self.format, name))
+    }
+
+    /*
+        Places the value in the named field, keeping only the bits that fit.
+    */
+    pub fn place(&self, name: &str, value: u16) -> u16 {
+        let field = self.field(name);
+        ((value as u32 & ((1 << field.width()) - 1)) << field.lo) as u16
+    }
+}
+
+impl Isa {
+    /*
+        Parses and validates an ISA description, panicking if it is
+        malformed or if two instructions share an encoding.
+    */
+    pub fn load(text: &str) -> Isa {
+        let isa = Isa::parse(text);
+        if let Err(msg) = isa.validate() {
+            panic!("Invalid ISA description: {}", msg);
+        }
+        isa
+    }
+
+    /*
+        Parses an ISA description without checking it for conflicting
+        encodings.
+    */
+    pub fn parse(text: &str) -> Isa {
+        let mut instructions: Vec<Encoding> = Vec::new();
+        let mut registers: Vec<(String, u16)> = Vec::new();
+        let mut section: Option<Format> = None;
+        let mut layouts: Vec<Layout> = Isa::default().layouts;
+        let mut in_registers = false;
+        let mut in_formats = false;
+        let mut saw_registers = false;
+        let mut rtype_opcode: u16 = 0;
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line = match raw_line.find("#") {
+                // register names start with '$' so '#' is always a comment
+                None => raw_line.trim(),
+                Some(i) => raw_line[..i].trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("[") && line.ends_with("]") {
+                in_registers = false;
+                in_formats = false;
+                section = match &line[1..line.len()-1] {
+                    "rtype" => Some(Format::R),
+                    "jtype" => Some(Format::J),
+                    "itype" => Some(Format::I),
+                    "iwide" => Some(Format::IWide),
+                    "registers" => {
+                        in_registers = true;
+                        saw_registers = true;
+                        None
+                    },
+                    "formats" => {
+                        in_formats = true;
+                        None
+                    },
+                    other => panic!("Unrecognized ISA section [{}] on line {}", other, idx + 1),
+                };
+                continue;
+            }
+            let parts: Vec<&str> = line.split("=").map(|s| s.trim()).collect();
+            if parts.len() != 2 || parts[0].is_empty() {
+                panic!("Expected `name = value` on line {}: {}", idx + 1, raw_line);
+            }
+            if in_formats {
+                let format = match parts[0] {
+                    "rtype" => Format::R,
+                    "jtype" => Format::J,
+                    "itype" => Format::I,
+                    "iwide" => Format::IWide,
+                    other => panic!("Unrecognized format {} on line {}", other, idx + 1),
+                };
+                let layout = parse_layout(format, parts[1], idx + 1);
+                layouts.retain(|l| l.format != format);
+                layouts.push(layout);
+                continue;
+            }
+            let (name, new) = match parts[0].strip_prefix("new ") {
+                Some(name) => (name.trim(), true),
+                None => (parts[0], false),
+            };
+            let value = parse_value(parts[1], idx + 1);
+            if in_registers {
+                if !name.starts_with("$") || name.len() < 2 {
+                    panic!("Register name {} on line {} does not start with $", name, idx + 1);
+                }
+                registers.push((name.to_string(), value));
+                continue;
+            }
+            let format = match section {
+                None => panic!("Entry outside of a section on line {}", idx + 1),
+                Some(format) => format,
+            };
+            if format == Format::R && name == "opcode" && !new {
+                rtype_opcode = value;
+                continue;
+            }
+            if !new && !known_mnemonics(format).contains(&name) {
+                panic!("Unrecognized {:?}-type instruction {} on line {}; declare instructions the default tables lack as `new {} = {}`",
+                    format, name, idx + 1, name, parts[1]);
+            }
+            instructions.push(Encoding {
+                mnemonic: name.to_string(),
+                format,
+                opcode: if format == Format::R { 0 } else { value },
+                funct: if format == Format::R { value } else { 0 },
+            });
+        }
+        for encoding in instructions.iter_mut().filter(|e| e.format == Format::R) {
+            encoding.opcode = rtype_opcode;
+        }
+        if !saw_registers {
+            registers = Isa::default().registers;
+        }
+        Isa { instructions, registers, layouts }
+    }
+
+    /*
+        Checks that every field fits its width and that no two
+        instructions or registers share an encoding.
+    */
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, a) in self.instructions.iter().enumerate() {
+            let layout = self.layout(a.format);
+            let opcode_bits = layout.field("op").width();
+            if a.opcode >= 1 << opcode_bits {
+                return Err(format!("opcode {} of {} does not fit in {} bits", a.opcode, a.mnemonic, opcode_bits));
+            }
+            let funct_bits = if a.format == Format::R { layout.field("funct").width() } else { 0 };
+            if a.funct >= 1 << funct_bits {
+                return Err(format!("funct {} of {} does not fit in {} bits", a.funct, a.mnemonic, funct_bits));
+            }
+            for b in self.instructions[..i].iter() {
+                if a.mnemonic == b.mnemonic {
+                    return Err(format!("{} is defined more than once", a.mnemonic));
+                }
+                let overlaps = if a.format == Format::R && b.format == Format::R {
+                    a.opcode == b.opcode && a.funct == b.funct
+                } else {
+                    a.opcode == b.opcode
+                };
+                if overlaps {
+                    return Err(format!("{} and {} have overlapping encodings", b.mnemonic, a.mnemonic));
+                }
+            }
+        }
+        let register_bits = self.layout(Format::R).field("r1").width();
+        for (i, (name, number)) in self.registers.iter().enumerate() {
+            if *number >= 1 << register_bits {
+                return Err(format!("register {} does not fit in {} bits", name, register_bits));
+            }
+            for (other, other_number) in self.registers[..i].iter() {
+                if name == other {
+                    return Err(format!("register {} is defined more than once", name));
+                }
+                if number == other_number {
+                    return Err(format!("registers {} and {} share number {}", other, name, number));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn encoding(&self, mnemonic: &str) -> &Encoding {
+        self.instructions
+            .iter()
+            .find(|e| e.mnemonic == mnemonic)
+            .unwrap_or_else(|| panic!("Instruction {} is not part of the loaded ISA", mnemonic))
+    }
+
+    pub fn register_number(&self, name: &str) -> u16 {
+        self.registers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, number)| *number)
+            .unwrap_or_else(|| panic!("Register {} is not part of the loaded ISA", name))
+    }
+
+    pub fn layout(&self, format: Format) -> &Layout {
+        self.layouts
+            .iter()
+            .find(|l| l.format == format)
+            .unwrap_or_else(|| panic!("No layout for {:?}-type instructions", format))
+    }
+
+    /*
+        Returns every instruction whose opcode (and funct, for r-type
+        instructions) matches the given word.
+    */
+    pub fn decode(&self, word: u16) -> Vec<&Encoding> {
+        self.instructions
+            .iter()
+            .filter(|e| {
+                let layout = self.layout(e.format);
+                layout.field("op").extract(word) == e.opcode
+                    && (e.format != Format::R || layout.field("funct").extract(word) == e.funct)
+            })
+            .collect()
+    }
+
+    /*
+        Decodes every possible 16-bit word against the instruction table,
+        collecting the words claimed by several instructions, the opcodes
+        with words nothing claims, and fields that share bits within a
+        format.
+    */
+    pub fn check(&self) -> CheckReport {
+        let mut conflicts: Vec<Conflict> = Vec::new();
+        let mut unused: BTreeMap<u16, u32> = BTreeMap::new();
+        let opcode_field = self.layout(Format::I).field("op").clone();
+        for word in 0..=u16::MAX {
+            let claims = self.decode(word);
+            if claims.is_empty() {
+                *unused.entry(opcode_field.extract(word)).or_insert(0) += 1;
+            } else if claims.len() > 1 {
+                let mnemonics: Vec<String> = claims.iter().map(|e| e.mnemonic.clone()).collect();
+                match conflicts.iter_mut().find(|c| c.mnemonics == mnemonics) {
+                    Some(conflict) => {
+                        conflict.words += 1;
+                        conflict.last = word;
+                    },
+                    None => conflicts.push(Conflict { mnemonics, words: 1, first: word, last: word }),
+                }
+            }
+        }
+        let mut field_overlaps = Vec::new();
+        for layout in self.layouts.iter() {
+            for (i, a) in layout.fields.iter().enumerate() {
+                for b in layout.fields[..i].iter() {
+                    if a.lo <= b.hi && b.lo <= a.hi {
+                        field_overlaps.push((layout.format, b.name.clone(), a.name.clone()));
+                    }
+                }
+            }
+        }
+        CheckReport {
+            conflicts,
+            unused: unused.into_iter().map(|(opcode, words)| Unused { opcode, words }).collect(),
+            field_overlaps,
+        }
+    }
+}
+
+impl CheckReport {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty() && self.field_overlaps.is_empty()
+    }
+}
+
+impl core::fmt::Display for CheckReport {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        for conflict in self.conflicts.iter() {
+            writeln!(f, "conflict: {} all claim {} words ({:#06X} - {:#06X})",
+                conflict.mnemonics.join(", "), conflict.words, conflict.first, conflict.last)?;
+        }
+        for (format, a, b) in self.field_overlaps.iter() {
+            writeln!(f, "overlap: fields {} and {} of the {:?}-type format share bits", a, b, format)?;
+        }
+        for unused in self.unused.iter() {
+            writeln!(f, "unused: opcode {:#06b} leaves {} words undecoded", unused.opcode, unused.words)?;
+        }
+        Ok(())
+    }
+}
+
+/*
+    Mnemonics a description may list without declaring them new.
+*/
+pub fn known_mnemonics(format: Format) -> Vec<&'static str> {
+    match format {
+        Format::R => RTYPE_MNEMONICS.to_vec(),
+        Format::J => JTYPE_MNEMONICS.to_vec(),
+        Format::I => ITYPE_MNEMONICS.iter().chain(OTHER_ITYPE_MNEMONICS.iter()).cloned().collect(),
+        Format::IWide => IWIDE_MNEMONICS.iter().chain(OTHER_IWIDE_MNEMONICS.iter()).cloned().collect(),
+    }
+}
+
+/*
+    Parses a layout such as `op[15:12] address[11:0]` into its fields.
+*/
+fn parse_layout(format: Format, text: &str, line: usize) -> Layout {
+    let fields: Vec<Field> = text
+        .split_whitespace()
+        .map(|field| {
+            let open = field.find("[");
+            let colon = field.find(":");
+            if open.is_none() || colon.is_none() || !field.ends_with("]") {
+                panic!("Expected `name[hi:lo]` on line {}: {}", line, field);
+            }
+            let hi = parse_value(&field[open.unwrap()+1..colon.unwrap()], line);
+            let lo = parse_value(&field[colon.unwrap()+1..field.len()-1], line);
+            if hi < lo || hi > 15 {
+                panic!("Invalid bit range in field {} on line {}", field, line);
+            }
+            Field { name: field[..open.unwrap()].to_string(), hi, lo }
+        })
+        .collect();
+    let layout = Layout { format, fields };
+    layout.field("op");
+    layout
+}
+
+pub fn parse_value(expr: &str, line: usize) -> u16 {
+    let value = parse_wide_value(expr, line);
+    if value > u16::MAX as u32 {
+        panic!("Error parsing number {} on line {}", expr, line);
+    }
+    value as u16
+}
+
+/*
+    Like parse_value, for quantities such as sizes that may span the whole
+    address space.
+*/
+pub fn parse_wide_value(expr: &str, line: usize) -> u32 {
+    let parsed = if let Some(hex) = expr.strip_prefix("0x") {
+        u32::from_str_radix(hex, 16)
+    } else if let Some(binary) = expr.strip_prefix("0b") {
+        u32::from_str_radix(binary, 2)
+    } else {
+        expr.parse::<u32>()
+    };
+    parsed.unwrap_or_else(|_| panic!("Error parsing number {} on line {}", expr, line))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::isa::*;
+
+    #[test]
+    fn default_matches_example_file() {
+        let text = include_str!("../examples/isa/default.isa");
+        assert_eq!(Isa::load(text), Isa::default());
+    }
+
+    #[test]
+    fn parse_retargets_encodings() {
+        let isa = Isa::load("[rtype]\nopcode = 0b1111\nadd = 3 # funct\n[itype]\nlw = 0x8\n[registers]\n$v0 = 5\n");
+        assert_eq!(isa.encoding("add").opcode, 15);
+        assert_eq!(isa.encoding("add").funct, 3);
+        assert_eq!(isa.encoding("lw").opcode, 8);
+        assert_eq!(isa.register_number("$v0"), 5);
+    }
+
+    #[test]
+    fn parse_takes_registers_and_functs_from_the_file() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n[registers]\n$0 = 0\n$t0 = 1\n");
+        assert_eq!(isa.encoding("xor").funct, 5);
+        assert_eq!(isa.registers, vec![(String::from("$0"), 0), (String::from("$t0"), 1)]);
+    }
+
+    #[test]
+    fn validate_rejects_overlapping_opcodes() {
+        let isa = Isa::parse("[jtype]\njmp = 9\n[iwide]\njmpi = 9\n");
+        assert_eq!(isa.validate(), Err(String::from("jmp and jmpi have overlapping encodings")));
+    }
+
+    #[test]
+    fn validate_rejects_rtype_opcode_overlap() {
+        let isa = Isa::parse("[rtype]\nopcode = 3\nadd = 0\n[itype]\nlw = 3\n");
+        assert_eq!(isa.validate(), Err(String::from("add and lw have overlapping encodings")));
+        let isa = Isa::parse("[rtype]\nadd = 0\nsub = 0\n");
+        assert_eq!(isa.validate(), Err(String::from("add and sub have overlapping encodings")));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unrecognized R-type instruction ad on line 2")]
+    fn parse_rejects_misspelled_mnemonics() {
+        Isa::parse("[rtype]\nad = 0\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid ISA description")]
+    fn load_rejects_wide_fields() {
+        Isa::load("[rtype]\nadd = 8\n");
+    }
+
+    #[test]
+    fn check_default_is_clean() {
+        let report = Isa::default().check();
+        assert!(report.is_clean());
+        assert_eq!(report.unused, vec![
+            Unused { opcode: 12, words: 4096 },
+            Unused { opcode: 13, words: 4096 },
+            Unused { opcode: 14, words: 4096 },
+            Unused { opcode: 15, words: 4096 },
+        ]);
+    }
+
+    #[test]
+    fn check_reports_shared_opcodes() {
+        let text = include_str!("../examples/isa/principles_of_operation.isa");
+        let report = Isa::parse(text).check();
+        assert_eq!(report.conflicts, vec![Conflict {
+            mnemonics: vec![String::from("jmpi"), String::from("jali"), String::from("li")],
+            words: 4096,
+            first: 0x9000,
+            last: 0x9FFF,
+        }]);
+    }
+
+    #[test]
+    fn check_reports_field_overlaps() {
+        let isa = Isa::parse("[rtype]\nadd = 0\nsub = 1\n[formats]\nrtype = op[15:12] r1[11:9] r2[9:6] funct[2:0]\n");
+        let report = isa.check();
+        assert_eq!(report.field_overlaps, vec![(Format::R, String::from("r1"), String::from("r2"))]);
+        assert_eq!(report.unused[0], Unused { opcode: 0, words: 6 * 512 });
+    }
+}
@@ -0,0 +1,26 @@
+/*
+    The assembler as a library, for programs that add their own output
+    formats: implement output::ImageWriter, pass its constructor to
+    output::register and then call cli::run with the command line.
+*/
+
+pub mod alu;
+pub mod archive;
+pub mod assembler;
+pub mod cli;
+pub mod control;
+pub mod debugger;
+pub mod devices;
+pub mod disasm;
+pub mod ir;
+pub mod isa;
+pub mod linker;
+pub mod memory;
+pub mod multicycle;
+pub mod object;
+pub mod output;
+pub mod peephole;
+pub mod reader;
+pub mod simulator;
+pub mod vectors;
+
@@ -0,0 +1,342 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+use archive::Archive;
+use assembler::similar_names;
+use isa::Format;
+use isa::Isa;
+use memory::MemoryLayout;
+use object::Object;
+use object::RelocKind;
+
+/*
+    Combines objects into one image. Sections of the same name are joined
+    in the order the objects are given and placed by the memory layout,
+    then every
+    relocation is patched with the final address of its symbol, looked up
+    among the object's own symbols first and then among the global ones.
+    Returns the image's words by address and the final global symbol
+    table.
+*/
+pub fn link(objects: &[(String, Object)], isa: &Isa, layout: &MemoryLayout) -> (Vec<(u16, u16)>, HashMap<String, u16>) {
+    // offset of every (object, section) pair within the joined section
+    let mut sizes: Vec<(String, u16)> = Vec::new();
+    let mut offsets: Vec<(usize, String, u16)> = Vec::new();
+    for (i, (_, object)) in objects.iter().enumerate() {
+        for section in object.sections.iter() {
+            let idx = match sizes.iter().position(|(name, _)| name == &section.name) {
+                Some(idx) => idx,
+                None => {
+                    sizes.push((section.name.clone(), 0));
+                    sizes.len() - 1
+                },
+            };
+            let joined = sizes[idx].1 as u32 + section.size as u32;
+            if joined > 1 << 16 {
+                panic!("Section {} needs {} words, more than the address space holds", section.name, joined);
+            }
+            offsets.push((i, section.name.clone(), sizes[idx].1));
+            sizes[idx].1 = joined as u16;
+        }
+    }
+    let section_bases = layout.place(&sizes);
+    let mut bases: HashMap<(usize, String), u16> = HashMap::new();
+    for (i, name, offset) in offsets.into_iter() {
+        let idx = sizes.iter().position(|(joined, _)| joined == &name).unwrap();
+        bases.insert((i, name), section_bases[idx] + offset);
+    }
+    let base = |i: usize, section: &str| -> u16 {
+        *bases
+            .get(&(i, section.to_string()))
+            .unwrap_or_else(|| panic!("{} refers to undeclared section {}", objects[i].0, section))
+    };
+
+    let mut symbols: HashMap<String, u16> = HashMap::new();
+    let mut locals: Vec<HashMap<String, u16>> = Vec::new();
+    let mut defined_in: HashMap<String, &str> = HashMap::new();
+    for (i, (file, object)) in objects.iter().enumerate() {
+        let mut own: HashMap<String, u16> = HashMap::new();
+        for symbol in object.symbols.iter() {
+            let addr = base(i, &symbol.section) + symbol.offset;
+            own.insert(symbol.name.clone(), addr);
+            if !symbol.global {
+                continue;
+            }
+            if let Some(other) = defined_in.get(&symbol.name) {
+                panic!("Symbol {} is defined in both {} and {}", symbol.name, other, file);
+            }
+            defined_in.insert(symbol.name.clone(), file);
+            symbols.insert(symbol.name.clone(), addr);
+        }
+        locals.push(own);
+    }
+
+    let mut image: HashMap<u16, u16> = HashMap::new();
+    for (i, (file, object)) in objects.iter().enumerate() {
+        for section in object.sections.iter() {
+            for (offset, word) in section.words.iter() {
+                let addr = base(i, &section.name) + offset;
+                if image.insert(addr, *word).is_some() {
+                    panic!("{} overlaps another object at address {:#X}", file, addr);
+                }
+            }
+        }
+    }
+
+    for (i, (file, object)) in objects.iter().enumerate() {
+        for reloc in object.relocations.iter() {
+            let site = base(i, &reloc.section) + reloc.offset;
+            let target = *locals[i]
+                .get(&reloc.symbol)
+                .or_else(|| symbols.get(&reloc.symbol))
+                .unwrap_or_else(|| panic!("{}", undefined_message(&reloc.symbol, file, objects, &symbols)));
+            let word = image
+                .get_mut(&site)
+                .unwrap_or_else(|| panic!("Relocation at {:#X} in {} has no word to patch", site, file));
+            *word = patch(*word, reloc.kind, site, target, isa)
+                .unwrap_or_else(|msg| panic!("Cannot relocate {} at {:#X} in {}: {}", reloc.symbol, site, file, msg));
+        }
+    }
+
+    let mut words: Vec<(u16, u16)> = image.into_iter().collect();
+    words.sort();
+    (words, symbols)
+}
+
+/*
+    The archive members needed to link the given objects: those defining
+    a global symbol that the objects, or members already pulled in, refer
+    to without defining. Archives are searched until nothing more is
+    needed, so members may depend on members of any archive.
+*/
+pub fn pull_members(objects: &[(String, Object)], archives: &[(String, Archive)]) -> Vec<(String, Object)> {
+    let mut needed: HashSet<String> = HashSet::new();
+    let mut defined: HashSet<String> = HashSet::new();
+    let note = |object: &Object, needed: &mut HashSet<String>, defined: &mut HashSet<String>| {
+        for symbol in object.symbols.iter().filter(|symbol| symbol.global) {
+            defined.insert(symbol.name.clone());
+        }
+        for reloc in object.relocations.iter() {
+            if !object.symbols.iter().any(|symbol| symbol.name == reloc.symbol) {
+                needed.insert(reloc.symbol.clone());
+            }
+        }
+    };
+    for (_, object) in objects.iter() {
+        note(object, &mut needed, &mut defined);
+    }
+
+    let mut pulled: Vec<(usize, usize)> = Vec::new();
+    loop {
+        let mut changed = false;
+        for (a, (_, archive)) in archives.iter().enumerate() {
+            for (m, (_, member)) in archive.members.iter().enumerate() {
+                let resolves = member.symbols
+                    .iter()
+                    .any(|symbol| symbol.global && needed.contains(&symbol.name) && !defined.contains(&symbol.name));
+                if resolves && !pulled.contains(&(a, m)) {
+                    note(member, &mut needed, &mut defined);
+                    pulled.push((a, m));
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    pulled
+        .into_iter()
+        .map(|(a, m)| {
+            let (file, archive) = &archives[a];
+            let (name, member) = &archive.members[m];
+            (format!("{}({})", file, name), member.clone())
+        })
+        .collect()
+}
+
+/*
+    Explains an unresolved reference, pointing at local symbols of that
+    name in other objects and at global symbols with similar names.
+*/
+fn undefined_message(name: &str, file: &str, objects: &[(String, Object)], symbols: &HashMap<String, u16>) -> String {
+    let mut message = format!("Undefined symbol {} referenced in {}", name, file);
+    let hidden: Vec<&str> = objects
+        .iter()
+        .filter(|(_, object)| object.symbols.iter().any(|symbol| symbol.name == name && !symbol.global))
+        .map(|(other, _)| other.as_str())
+        .collect();
+    if !hidden.is_empty() {
+        message += &format!(" ({} is local to {}; export it with .globl)", name, hidden.join(", "));
+    }
+    let candidates = similar_names(name, symbols.keys());
+    if !candidates.is_empty() {
+        message += &format!(" (did you mean {}?)", candidates.join(", "));
+    }
+    message
+}
+
+/*
+    Fills in the field a relocation refers to, checking that the value
+    fits it.
+*/
+fn patch(word: u16, kind: RelocKind, site: u16, target: u16, isa: &Isa) -> Result<u16, String> {
+    let (format, name, value) = match kind {
+        RelocKind::Jump => {
+            let field = isa.layout(Format::J).field("address");
+            let region = !field.mask() >> field.lo;
+            if (site + 1) & region != target & region {
+                return Err(format!("target {:#X} is outside the region of the jump", target));
+            }
+            (Format::J, "address", target)
+        },
+        RelocKind::Branch => {
+            let field = isa.layout(Format::I).field("imm");
+            let offset = target as i32 - (site as i32 + 1);
+            let limit = 1 << (field.width() - 1);
+            if offset < -limit || offset >= limit {
+                return Err(format!("target is {} words away, beyond the branch range", offset));
+            }
+            (Format::I, "imm", offset as u16)
+        },
+        // bytes of the byte address, loaded by lli %lo and then lui %hi
+        RelocKind::Lo => (Format::IWide, "imm", (target << 1) & 0xFF),
+        RelocKind::Hi => (Format::IWide, "imm", (target << 1) >> 8),
+    };
+    let layout = isa.layout(format);
+    Ok((word & !layout.field(name).mask()) | layout.place(name, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{archive::Archive, assembler::assemble, assembler::Options, isa::Isa, linker::pull_members, memory::MemoryLayout, object::Object, output::Image, simulator::Machine};
+
+    fn link(objects: &[(String, Object)], isa: &Isa) -> (Vec<(u16, u16)>, std::collections::HashMap<String, u16>) {
+        crate::linker::link(objects, isa, &MemoryLayout::default())
+    }
+
+    fn object(name: &str, text: &str) -> (String, Object) {
+        let options = Options { object: true, ..Options::default() };
+        (name.to_string(), Object::from_program(&assemble(text, &options), &Isa::default()))
+    }
+
+    #[test]
+    fn link_resolves_across_objects() {
+        let main = object("main.o", ".globl main\n.extern msg\n.extern print\nmain:\nlli $a0, %lo(msg)\nlui $a0, %hi(msg)\njal print\nbeq $0, $0, main\n");
+        let lib = object("lib.o", ".globl print\n.globl msg\n.extern main\nprint:\nbne $v0, $0, main\njmpi 0($ra)\nmsg:\n.asciiz \"hi\"\n");
+        let (words, symbols) = link(&[main, lib], &Isa::default());
+        assert_eq!(symbols.get("print"), Some(&4));
+        assert_eq!(symbols.get("msg"), Some(&6));
+        assert_eq!(words, vec![
+            (0, 0xA80C), // lli $a0, 12 (byte address of msg)
+            (1, 0xB800), // lui $a0, 0
+            (2, 0x2004), // jal print
+            (3, 0x503C), // beq $0, $0, -4
+            (4, 0x643B), // bne $v0, $0, -5
+            (5, 0x8E00), // jmpi 0($ra)
+            (6, 0x68),
+            (7, 0x69),
+        ]);
+    }
+
+    #[test]
+    fn linked_address_loads_run() {
+        // the low byte of msg's byte address 0x180 has its top bit set
+        let main = object("main.o", ".globl main\n.extern msg\nmain:\nlli $a0, %lo(msg)\nlui $a0, %hi(msg)\ndone:\nbeq $0, $0, done\n");
+        let data = object("data.o", ".globl msg\n.space 0xBD\nmsg:\n.word 7\n");
+        let (words, symbols) = link(&[main, data], &Isa::default());
+        assert_eq!(symbols.get("msg"), Some(&0xC0));
+        let mut machine = Machine::new(&Isa::default());
+        machine.load(&Image::from_words(words, &symbols));
+        machine.run(100).unwrap();
+        assert_eq!(machine.registers[4], 0x180);
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined symbol print referenced in main.o")]
+    fn link_rejects_undefined_symbols() {
+        link(&[object("main.o", ".extern print\njal print\n")], &Isa::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined symbol print referenced in main.o (print is local to lib.o; export it with .globl)")]
+    fn link_keeps_symbols_local() {
+        let lib = object("lib.o", "print:\n");
+        link(&[object("main.o", ".extern print\njal print\n"), lib], &Isa::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined symbol prnt referenced in main.o (did you mean print?)")]
+    fn link_suggests_similar_symbols() {
+        let lib = object("lib.o", ".globl print\nprint:\n");
+        link(&[object("main.o", ".extern prnt\njal prnt\n"), lib], &Isa::default());
+    }
+
+    #[test]
+    fn link_prefers_own_local_symbols() {
+        let a = object("a.o", "jmp f\nf:\n");
+        let b = object("b.o", ".globl f\nf:\n");
+        let (words, symbols) = link(&[a, b], &Isa::default());
+        assert_eq!(symbols.get("f"), Some(&1));
+        assert_eq!(words, vec![(0, 0x1001)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Symbol f is defined in both a.o and b.o")]
+    fn link_rejects_duplicate_symbols() {
+        link(&[object("a.o", ".globl f\nf:\n"), object("b.o", ".globl f\nf:\n")], &Isa::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "beyond the branch range")]
+    fn link_checks_branch_range() {
+        let far = object("far.o", ".globl far\n.space 40\nfar:\n");
+        link(&[object("main.o", ".extern far\nbeq $0, $0, far\n"), far], &Isa::default());
+    }
+
+    #[test]
+    fn link_joins_sections_by_layout() {
+        let layout = MemoryLayout::load("[regions]\nrom = 0 0x100\nram = 0x100 0x100\n[sections]\n.text = rom\n.data = ram\n");
+        let a = object("a.o", ".globl x\n.extern y\n.data\nx:\n.word 1\n.text\njmp y\n");
+        let b = object("b.o", ".globl y\n.extern x\n.data\n.word 2\n.text\ny:\nlli $v0, %lo(x)\n");
+        let (words, symbols) = crate::linker::link(&[a, b], &Isa::default(), &layout);
+        assert_eq!(symbols.get("x"), Some(&0x100));
+        assert_eq!(symbols.get("y"), Some(&1));
+        assert_eq!(words, vec![(0, 0x1001), (1, 0xA400), (0x100, 1), (0x101, 2)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Region rom holds 0x2 words but its sections need 0x3: .text 0x3")]
+    fn link_reports_region_overflows() {
+        let layout = MemoryLayout::load("[regions]\nrom = 0 2\n");
+        let a = object("a.o", ".word 1\n.word 2\n");
+        let b = object("b.o", ".word 3\n");
+        crate::linker::link(&[a, b], &Isa::default(), &layout);
+    }
+
+    #[test]
+    fn pull_only_needed_members() {
+        let main = object("main.o", ".extern square\njal square\n");
+        let libm = Archive {
+            members: vec![
+                object("square.o", ".globl square\n.extern mul\nsquare:\njal mul\n"),
+                object("cube.o", ".globl cube\n.extern mul\ncube:\njal mul\n"),
+            ],
+        };
+        let libc = Archive {
+            members: vec![
+                object("mul.o", ".globl mul\nmul:\njmpi 0($ra)\n"),
+                object("div.o", ".globl div\ndiv:\njmpi 0($ra)\n"),
+            ],
+        };
+        let archives = vec![(String::from("libc.a"), libc), (String::from("libm.a"), libm)];
+        let pulled = pull_members(std::slice::from_ref(&main), &archives);
+        let names: Vec<&str> = pulled.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, vec!["libm.a(square.o)", "libc.a(mul.o)"]);
+
+        let mut objects = vec![main];
+        objects.extend(pulled);
+        let (words, _) = link(&objects, &Isa::default());
+        assert_eq!(words, vec![(0, 0x2001), (1, 0x2002), (2, 0x8E00)]);
+    }
+}
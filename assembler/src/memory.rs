@@ -0,0 +1,241 @@
+/*
+    Memory layout of the target: the ROM and RAM regions that sections are
+    placed in. The default layout puts every section in one region
+    covering the 16K words of memory; other layouts can be loaded from a
+    description file of the form:
+
+        # comment
+        [regions]
+        rom = 0x0000 0x2000     # base word address and size in words
+        ram = 0x2000 0x2000
+        [sections]
+        .text = rom             # region the section is placed in
+        .data = ram
+        .bss = ram
+
+    Sections mapped to the same region are placed one after the other in
+    the order they are listed. Sections a description leaves out go after
+    them in the first region, in the order they first appear.
+*/
+
+use isa::{parse_value, parse_wide_value};
+
+pub const MEMORY_WORDS: u32 = 0x4000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub name: String,
+    pub base: u16,
+    pub size: u32, // in words, wide enough for regions that end the address space
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryLayout {
+    pub regions: Vec<Region>,
+    pub sections: Vec<(String, String)>, // section name and region name
+}
+
+impl Default for MemoryLayout {
+    fn default() -> MemoryLayout {
+        let region = String::from("memory");
+        MemoryLayout {
+            regions: vec![Region { name: region.clone(), base: 0, size: MEMORY_WORDS }],
+            sections: [".text", ".data", ".bss"]
+                .iter()
+                .map(|section| (section.to_string(), region.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl MemoryLayout {
+    pub fn parse(text: &str) -> MemoryLayout {
+        let mut layout = MemoryLayout { regions: vec![], sections: vec![] };
+        let mut in_regions: Option<bool> = None;
+        for (idx, raw_line) in text.lines().enumerate() {
+            let line = match raw_line.find("#") {
+                None => raw_line.trim(),
+                Some(i) => raw_line[..i].trim(),
+            };
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with("[") && line.ends_with("]") {
+                in_regions = match &line[1..line.len()-1] {
+                    "regions" => Some(true),
+                    "sections" => Some(false),
+                    other => panic!("Unrecognized memory layout section [{}] on line {}", other, idx + 1),
+                };
+                continue;
+            }
+            let parts: Vec<&str> = line.split("=").map(|s| s.trim()).collect();
+            if parts.len() != 2 || parts[0].is_empty() {
+                panic!("Expected `name = value` on line {}: {}", idx + 1, raw_line);
+            }
+            match in_regions {
+                Some(true) => {
+                    let values: Vec<&str> = parts[1].split_whitespace().collect();
+                    if values.len() != 2 {
+                        panic!("Expected a base and a size on line {}: {}", idx + 1, raw_line);
+                    }
+                    layout.regions.push(Region {
+                        name: parts[0].to_string(),
+                        base: parse_value(values[0], idx + 1),
+                        size: parse_wide_value(values[1], idx + 1),
+                    });
+                },
+                Some(false) => layout.sections.push((parts[0].to_string(), parts[1].to_string())),
+                None => panic!("Expected [regions] or [sections] before line {}", idx + 1),
+            }
+        }
+        layout
+    }
+
+    pub fn load(text: &str) -> MemoryLayout {
+        let layout = MemoryLayout::parse(text);
+        layout.validate().unwrap_or_else(|msg| panic!("Invalid memory layout: {}", msg));
+        layout
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.regions.is_empty() {
+            return Err(String::from("no regions are defined"));
+        }
+        for (i, region) in self.regions.iter().enumerate() {
+            if region.size == 0 || region.base as u32 + region.size > 1 << 16 {
+                return Err(format!("region {} does not fit the address space", region.name));
+            }
+            for other in self.regions[..i].iter() {
+                if other.name == region.name {
+                    return Err(format!("region {} is defined twice", region.name));
+                }
+                if region.base as u32 + region.size > other.base as u32 && (other.base as u32) + other.size > region.base as u32 {
+                    return Err(format!("regions {} and {} overlap", other.name, region.name));
+                }
+            }
+        }
+        for (i, (section, region)) in self.sections.iter().enumerate() {
+            if self.region(region).is_none() {
+                return Err(format!("section {} is mapped to undefined region {}", section, region));
+            }
+            if self.sections[..i].iter().any(|(other, _)| other == section) {
+                return Err(format!("section {} is mapped twice", section));
+            }
+        }
+        Ok(())
+    }
+
+    /*
+        Words of memory the layout spans, from address 0 to the end of its
+        last region.
+    */
+    pub fn words(&self) -> u32 {
+        self.regions.iter().map(|region| region.base as u32 + region.size).max().unwrap_or(0)
+    }
+
+    pub fn region(&self, name: &str) -> Option<&Region> {
+        self.regions.iter().find(|region| region.name == name)
+    }
+
+    /*
+        Base address of each of the given sections, in the order given.
+        Panics with every region whose sections don't fit in it.
+    */
+    pub fn place(&self, sizes: &[(String, u16)]) -> Vec<u16> {
+        let region_of = |section: &str| -> &Region {
+            match self.sections.iter().find(|(name, _)| name == section) {
+                Some((_, region)) => self.region(region).unwrap(),
+                None => &self.regions[0],
+            }
+        };
+        // listed sections first, in the order of the description
+        let mut order: Vec<usize> = Vec::new();
+        for (listed, _) in self.sections.iter() {
+            order.extend(sizes.iter().position(|(name, _)| name == listed));
+        }
+        let unlisted: Vec<usize> = (0..sizes.len()).filter(|i| !order.contains(i)).collect();
+        order.extend(unlisted);
+
+        let mut bases: Vec<u16> = vec![0; sizes.len()];
+        let mut overflows: Vec<String> = Vec::new();
+        for region in self.regions.iter() {
+            let mut next = region.base as u32;
+            let mut contents: Vec<String> = Vec::new();
+            for i in order.iter().cloned().filter(|i| region_of(&sizes[*i].0).name == region.name) {
+                let (name, size) = &sizes[i];
+                bases[i] = next as u16;
+                next += *size as u32;
+                contents.push(format!("{} {:#X}", name, size));
+            }
+            let used = next - region.base as u32;
+            if used > region.size {
+                overflows.push(format!("Region {} holds {:#X} words but its sections need {:#X}: {}",
+                    region.name, region.size, used, contents.join(", ")));
+            }
+        }
+        if !overflows.is_empty() {
+            panic!("{}", overflows.join("\n"));
+        }
+        bases
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::memory::*;
+
+    static ROM_RAM: &str = "
+        [regions]
+        rom = 0x0000 0x100   # code
+        ram = 0x2000 0x10
+        [sections]
+        .text = rom
+        .data = ram
+        .bss = ram
+    ";
+
+    #[test]
+    fn default_matches_example_file() {
+        let text = include_str!("../examples/memory/default.layout");
+        assert_eq!(MemoryLayout::load(text), MemoryLayout::default());
+    }
+
+    #[test]
+    fn place_sections_in_regions() {
+        let layout = MemoryLayout::load(ROM_RAM);
+        let sizes = vec![
+            (String::from(".bss"), 4),
+            (String::from(".text"), 0x20),
+            (String::from(".data"), 3),
+            (String::from(".vectors"), 2),
+        ];
+        assert_eq!(layout.place(&sizes), vec![0x2003, 0, 0x2000, 0x20]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Region ram holds 0x10 words but its sections need 0x14: .data 0x10, .bss 0x4")]
+    fn place_reports_overflows() {
+        let sizes = vec![(String::from(".data"), 0x10), (String::from(".bss"), 4)];
+        MemoryLayout::load(ROM_RAM).place(&sizes);
+    }
+
+    #[test]
+    fn regions_may_end_the_address_space() {
+        let layout = MemoryLayout::load("[regions]\nrom = 0 0x8000\nram = 0x8000 0x8000\n");
+        assert_eq!(layout.region("ram").unwrap().size, 0x8000);
+        assert_eq!(layout.words(), 0x10000);
+        assert_eq!(MemoryLayout::load("[regions]\nmemory = 0 0x10000\n").words(), 0x10000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid memory layout: regions rom and ram overlap")]
+    fn load_rejects_overlapping_regions() {
+        MemoryLayout::load("[regions]\nrom = 0 0x100\nram = 0x80 0x100\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid memory layout: section .data is mapped to undefined region sram")]
+    fn load_rejects_undefined_regions() {
+        MemoryLayout::load("[regions]\nrom = 0 0x100\n[sections]\n.data = sram\n");
+    }
+}
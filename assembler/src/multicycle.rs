@@ -0,0 +1,440 @@
+/*
+    Cycle-level model of the multicycle datapath, for checking the hardware
+    control unit cycle by cycle. Each instruction walks the states it
+    needs, and every cycle records the control lines of the principles of
+    operation. Under the default control specification the states are:
+
+        fetch       IR = MEM[PC], PC = PC + 1               every instruction
+        decode      read registers, ALUOut = PC + imm       every instruction
+        execute     ALU operation, branches and jumps end   every instruction
+        memory      lw reads, sw writes and ends            lw, sw
+        writeback   register write from memory or the ALU   r-type, addi, subi, lw, lli, lui
+
+    Jumps that link write $ra in their execute cycle, from the PC, on the
+    same clock edge that loads the jump target into the PC. Architectural
+    state lives in a simulator Machine, so programs, devices and the final
+    registers are the same as in the instruction-level simulator. The ALU
+    status lines latch the execute cycle's operation; the PC increment and
+    branch target of fetch and decode leave them alone.
+
+    The states and lines of each instruction come from a control
+    specification, the default one unless another is given, which the
+    truth tables, ROM and test vectors are generated from too, so they
+    describe the control unit the model runs.
+*/
+
+use alu::alu;
+use alu::AluFunction;
+use alu::Flags;
+use control::ControlSpec;
+use isa::Encoding;
+use isa::Format;
+use simulator::Machine;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    Fetch,
+    Decode,
+    Execute,
+    Memory,
+    Writeback,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PcSource {
+    Hold,
+    Sequential,
+    Branch,
+    Jump,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemAddr {
+    PC,
+    ALU,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemAccess {
+    Idle,
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegAccess {
+    Read,
+    Write,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SecondOp {
+    Immediate,
+    Reg2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegSource {
+    Memory,
+    ALU,
+    PC, // the link written by jal and jali
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControlLines {
+    pub branch_or_jump_or_sequential: PcSource,
+    pub mem_addr_from_pc_or_alu: MemAddr,
+    pub mem_read_or_write: MemAccess,
+    pub load_ir: bool,
+    pub reg_read_or_write: RegAccess,
+    pub second_op_from_immediate_or_reg2: SecondOp,
+    pub write_reg_from_memory_or_alu: RegSource,
+    pub alu_function: AluFunction,
+}
+
+impl Default for ControlLines {
+    fn default() -> ControlLines {
+        ControlLines {
+            branch_or_jump_or_sequential: PcSource::Hold,
+            mem_addr_from_pc_or_alu: MemAddr::PC,
+            mem_read_or_write: MemAccess::Idle,
+            load_ir: false,
+            reg_read_or_write: RegAccess::Read,
+            second_op_from_immediate_or_reg2: SecondOp::Reg2,
+            write_reg_from_memory_or_alu: RegSource::ALU,
+            alu_function: AluFunction::Add,
+        }
+    }
+}
+
+impl ControlLines {
+    /*
+        The lines packed into a control word, each as the number of its
+        value in declaration order:
+
+            [15:14] BranchOrJumpOrSequential     [8]   SecondOpComingFromImmediateOrReg2
+            [13]    MemAddrFromPCOrALU           [7:6] WriteRegFromMemoryOrALU
+            [12:11] MemReadOrWrite               [5:2] ALUFunction
+            [10]    LoadIR                       [1:0] zero
+            [9]     RegReadOrWrite
+    */
+    pub fn word(&self) -> u16 {
+        (self.branch_or_jump_or_sequential as u16) << 14
+            | (self.mem_addr_from_pc_or_alu as u16) << 13
+            | (self.mem_read_or_write as u16) << 11
+            | (self.load_ir as u16) << 10
+            | (self.reg_read_or_write as u16) << 9
+            | (self.second_op_from_immediate_or_reg2 as u16) << 8
+            | (self.write_reg_from_memory_or_alu as u16) << 6
+            | (self.alu_function as u16) << 2
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cycle {
+    pub number: u64,
+    pub state: State,
+    pub pc: u16, // at the start of the cycle
+    pub ir: u16, // at the end of the cycle
+    pub lines: ControlLines,
+    pub flags: Flags, // at the end of the cycle
+}
+
+pub const TRACE_HEADER: &str = "Cycle,State,PC,IR,BranchOrJumpOrSequential,MemAddrFromPCOrALU,MemReadOrWrite,LoadIR,RegReadOrWrite,SecondOpComingFromImmediateOrReg2,WriteRegFromMemoryOrALU,ALUFunction,Zero,Negative,CarryOut,CarryIn,Overflow";
+
+impl core::fmt::Display for Cycle {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let lines = &self.lines;
+        let flags = &self.flags;
+        write!(f, "{},{:?},{:04X},{:04X},{:?},{:?},{:?},{},{:?},{:?},{:?},{:?},{},{},{},{},{}",
+            self.number, self.state, self.pc, self.ir,
+            lines.branch_or_jump_or_sequential, lines.mem_addr_from_pc_or_alu, lines.mem_read_or_write,
+            lines.load_ir as u8, lines.reg_read_or_write, lines.second_op_from_immediate_or_reg2,
+            lines.write_reg_from_memory_or_alu, lines.alu_function,
+            flags.zero as u8, flags.negative as u8, flags.carry_out as u8, flags.carry_in as u8, flags.overflow as u8)
+    }
+}
+
+pub struct Multicycle {
+    pub machine: Machine,
+    pub spec: ControlSpec,
+    pub state: State,
+    pub cycles: u64,
+    pub trace: Vec<Cycle>,
+    // datapath registers between states
+    ir: u16,
+    a: u16,
+    b: u16,
+    alu_out: u16,
+    mdr: u16,
+    start: u16, // address of the instruction in flight
+    encoding: Option<Encoding>,
+}
+
+impl Multicycle {
+    pub fn new(machine: Machine, spec: ControlSpec) -> Multicycle {
+        Multicycle {
+            machine,
+            spec,
+            state: State::Fetch,
+            cycles: 0,
+            trace: Vec::new(),
+            ir: 0,
+            a: 0,
+            b: 0,
+            alu_out: 0,
+            mdr: 0,
+            start: 0,
+            encoding: None,
+        }
+    }
+
+    /*
+        Runs until the program stops, panicking if it hasn't within
+        max_steps instructions. Fails with the first instruction the
+        datapath cannot execute.
+    */
+    pub fn run(&mut self, max_steps: u64) -> Result<(), String> {
+        while !self.machine.halted {
+            if self.machine.steps >= max_steps {
+                panic!("Program did not stop within {} steps; PC is {:#06X}", max_steps, self.machine.pc);
+            }
+            self.cycle()?;
+        }
+        Ok(())
+    }
+
+    /*
+        Runs the current state for one clock cycle, moving data as its
+        control lines say, and records the lines.
+    */
+    pub fn cycle(&mut self) -> Result<(), String> {
+        if self.machine.halted {
+            return Ok(());
+        }
+        let pc = self.machine.pc;
+        if self.state == State::Fetch {
+            if pc as usize >= self.machine.memory.len() || !self.machine.loaded[pc as usize] {
+                self.machine.halted = true;
+                return Ok(());
+            }
+            self.start = pc;
+        }
+        let mut lines = self.spec.lines(self.encoding.as_ref(), self.state, false)?;
+
+        // the ALU and the values on the datapath's buses
+        let mut alu_result = self.alu_out;
+        let mut jump_target = pc;
+        match self.state {
+            State::Fetch => alu_result = alu(AluFunction::Add, pc, 1).result,
+            State::Decode => {
+                let encoding = match self.machine.isa().decode(self.ir).first() {
+                    Some(encoding) => (*encoding).clone(),
+                    None => return Err(format!("Illegal instruction {:04X} at {:#06X}", self.ir, self.start)),
+                };
+                let (first, second) = match encoding.format {
+                    Format::R => (self.field(&encoding, "r2"), self.field(&encoding, "r3")),
+                    Format::I => (self.field(&encoding, "r2"), self.field(&encoding, "r1")),
+                    Format::IWide => (self.field(&encoding, "r1"), 0),
+                    Format::J => (0, 0),
+                };
+                self.a = self.machine.registers[first as usize];
+                self.b = self.machine.registers[second as usize];
+                if encoding.format == Format::I {
+                    alu_result = alu(AluFunction::Add, pc, self.immediate(&encoding)).result;
+                }
+                self.encoding = Some(encoding);
+            },
+            State::Execute => {
+                let encoding = self.encoding.clone().unwrap();
+                let second = match lines.second_op_from_immediate_or_reg2 {
+                    SecondOp::Reg2 => self.b,
+                    // j-type targets bypass the ALU, leaving its status lines alone
+                    SecondOp::Immediate if encoding.format == Format::J => 0,
+                    // jmpi and jali offsets count instructions
+                    SecondOp::Immediate if encoding.format == Format::IWide && lines.branch_or_jump_or_sequential == PcSource::Jump =>
+                        self.immediate(&encoding) << 1,
+                    SecondOp::Immediate => self.immediate(&encoding),
+                };
+                let output = alu(lines.alu_function, self.a, second);
+                if encoding.format != Format::J {
+                    self.machine.flags = output.flags;
+                }
+                lines = self.spec.lines(Some(&encoding), self.state, output.flags.zero)?;
+                alu_result = output.result;
+                jump_target = if encoding.format == Format::J {
+                    let field = self.machine.isa().layout(Format::J).field("address");
+                    (pc & !(field.mask() >> field.lo)) | field.extract(self.ir)
+                } else {
+                    output.result >> 1
+                };
+            },
+            State::Memory | State::Writeback => (),
+        }
+
+        // everything below happens on the clock edge ending the cycle
+        if lines.mem_read_or_write != MemAccess::Idle {
+            let address = match lines.mem_addr_from_pc_or_alu {
+                MemAddr::PC => pc << 1,
+                MemAddr::ALU => self.alu_out,
+            };
+            if lines.mem_read_or_write == MemAccess::Write {
+                let value = self.b;
+                self.machine.store_word(address, value)?;
+            } else if lines.load_ir {
+                self.ir = self.machine.memory[pc as usize];
+            } else {
+                self.mdr = self.machine.load_word(address)?;
+            }
+        }
+        if lines.reg_read_or_write == RegAccess::Write {
+            let (register, value) = match lines.write_reg_from_memory_or_alu {
+                RegSource::Memory => (self.field(self.encoding.as_ref().unwrap(), "r1"), self.mdr),
+                RegSource::ALU => (self.field(self.encoding.as_ref().unwrap(), "r1"), self.alu_out),
+                RegSource::PC => (self.machine.isa().register_number("$ra"), pc << 1),
+            };
+            self.machine.set_register(register as usize, value);
+        }
+        self.machine.pc = match lines.branch_or_jump_or_sequential {
+            PcSource::Hold => pc,
+            PcSource::Sequential => alu_result,
+            PcSource::Branch => self.alu_out,
+            PcSource::Jump => jump_target,
+        };
+        self.alu_out = alu_result;
+
+        self.cycles += 1;
+        self.trace.push(Cycle { number: self.cycles, state: self.state, pc, ir: self.ir, lines, flags: self.machine.flags });
+        self.state = match &self.encoding {
+            Some(encoding) => self.spec.next_state(encoding, self.state),
+            None => State::Decode,
+        };
+        if self.state == State::Fetch {
+            self.encoding = None;
+            self.machine.steps += 1;
+            if self.machine.pc == self.start {
+                self.machine.halted = true;
+            }
+        }
+        Ok(())
+    }
+
+    fn field(&self, encoding: &Encoding, name: &str) -> u16 {
+        self.machine.isa().layout(encoding.format).field(name).extract(self.ir)
+    }
+
+    fn immediate(&self, encoding: &Encoding) -> u16 {
+        let field = self.machine.isa().layout(encoding.format).field("imm");
+        let shift = 16 - field.width();
+        ((field.extract(self.ir) << shift) as i16 >> shift) as u16
+    }
+
+    pub fn trace_csv(&self) -> String {
+        let mut text = String::from(TRACE_HEADER);
+        text.push('\n');
+        for cycle in self.trace.iter() {
+            text += &format!("{}\n", cycle);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{alu::*, assembler::assemble, assembler::image, assembler::Options, isa::Isa, multicycle::*};
+
+    fn machine(source: &str) -> Machine {
+        let isa = Isa::default();
+        let mut machine = Machine::new(&isa);
+        machine.load(&image(&assemble(source, &Options::default()), &isa));
+        machine
+    }
+
+    #[test]
+    fn matches_instruction_level_simulator() {
+        let source = "
+            count:
+            .word 0
+            main:
+            li $a0, 300
+            loop:
+            jal bump
+            addi $a0, $a0, -1
+            bne $a0, $0, loop
+            lli $v0, %lo(count)
+            lw $v0, 0($v0)
+            lli $at, 3
+            sra $v1, $v0, $at
+            nor $a1, $v1, $0
+            lui $a1, 0x7F
+            beq $0, $0, end
+            .word 0xFFFF
+            end:
+            beq $0, $0, end
+            bump:
+            lli $v1, %lo(count)
+            lw $sp, 0($v1)
+            addi $sp, $sp, 3
+            sw $sp, 0($v1)
+            jmpi 0($ra)
+        ";
+        let mut reference = machine(source);
+        reference.run(100000).unwrap();
+        let mut model = Multicycle::new(machine(source), ControlSpec::default());
+        model.run(100000).unwrap();
+        assert_eq!(model.machine.registers, reference.registers);
+        assert_eq!(model.machine.flags, reference.flags);
+        assert_eq!(model.machine.memory, reference.memory);
+        assert_eq!((model.machine.pc, model.machine.steps), (reference.pc, reference.steps));
+    }
+
+    #[test]
+    fn control_lines_per_cycle() {
+        let mut model = Multicycle::new(machine("lli $a0, 2\nlw $v0, 4($a0)\nadd $v1, $v0, $v0\n.word 7"), ControlSpec::default());
+        while model.machine.steps < 3 {
+            model.cycle().unwrap();
+        }
+        let states: Vec<State> = model.trace.iter().map(|cycle| cycle.state).collect();
+        use crate::multicycle::State::*;
+        assert_eq!(states, vec![
+            Fetch, Decode, Execute, Writeback,
+            Fetch, Decode, Execute, Memory, Writeback,
+            Fetch, Decode, Execute, Writeback,
+        ]);
+        assert_eq!(model.machine.registers[3], 14);
+        assert_eq!(model.trace[7].lines, ControlLines {
+            mem_addr_from_pc_or_alu: MemAddr::ALU,
+            mem_read_or_write: MemAccess::Read,
+            ..ControlLines::default()
+        });
+        assert_eq!(model.trace[8].lines.write_reg_from_memory_or_alu, RegSource::Memory);
+        assert_eq!(model.trace[0].lines.word(), 0x4C40);
+        assert_eq!(model.trace[7].lines.word(), 0x2940);
+        assert_eq!(model.trace[11].lines.alu_function, AluFunction::Add);
+        assert_eq!(model.trace_csv().lines().nth(1).unwrap(),
+            "1,Fetch,0000,A802,Sequential,PC,Read,1,Read,Immediate,ALU,Add,0,0,0,0,0");
+        assert_eq!(model.trace_csv().lines().nth(3).unwrap(),
+            "3,Execute,0001,A802,Hold,PC,Idle,0,Read,Immediate,ALU,LoadLower,0,0,0,0,0");
+    }
+
+    #[test]
+    fn runs_the_given_control_spec() {
+        // a control unit that never writes back r-type results
+        let text = include_str!("../examples/control/default.control").replace("[writeback: rtype addi", "[writeback: addi");
+        let mut model = Multicycle::new(machine("lli $v0, 2\nadd $v1, $v0, $v0\n"), ControlSpec::parse(&text));
+        model.run(10).unwrap();
+        let states: Vec<State> = model.trace.iter().map(|cycle| cycle.state).collect();
+        assert_eq!(&states[4..], &[State::Fetch, State::Decode, State::Execute]);
+        assert_eq!(model.machine.registers[3], 0);
+    }
+
+    #[test]
+    fn instructions_without_alu_functions_fail() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n");
+        let mut machine = Machine::new(&isa);
+        machine.load(&image(&assemble("xor $v0, $0, $v0\n", &Options::default()), &isa));
+        let mut model = Multicycle::new(machine, ControlSpec::default());
+        assert_eq!(model.run(10), Err(String::from("The ALU has no function for xor")));
+        assert_eq!(model.state, State::Execute);
+    }
+}
@@ -0,0 +1,333 @@
+/*
+    Relocatable object files, written by `-c` and combined by `link`. An
+    object is plain text with one record per line:
+
+        object 1                        format version
+        section .text 1A                section name and size in words
+        data .text 0 A808 B8FF 4500     words stored from a section offset on
+        symbol main .text 3 global      label defined at a section offset,
+                                        global if exported with .globl
+        extern print                    symbol declared with .extern
+        reloc .text 4 jump print        field to patch once print is placed
+
+    Offsets, sizes and words are hexadecimal. Local symbols only resolve
+    relocations of their own object. Each relocation names the
+    word to patch and the symbol whose final address goes into it, the
+    word itself holding the instruction with that field zeroed:
+
+        jump    address field of jmp/jal, the symbol's word address, which
+                must lie in the region of the instruction after the jump
+        branch  immediate of beq/bne, the distance from the instruction
+                after the branch to the symbol
+        lo, hi  immediate of lli/lui, the lower/upper byte of the symbol's
+                byte address, for `%lo(label)` and `%hi(label)`; as lli
+                sign-extends, an address is loaded with lli %lo first
+                and lui %hi after it
+*/
+
+use assembler::instr_to_words;
+use assembler::Program;
+use ir::Half;
+use ir::IRInstruction;
+use isa::Isa;
+
+const VERSION: u16 = 1;
+const WORDS_PER_RECORD: usize = 8;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub externs: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub size: u16,
+    pub words: Vec<(u16, u16)>, // offset and word
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub section: String,
+    pub offset: u16,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelocKind {
+    Jump,
+    Branch,
+    Hi,
+    Lo,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relocation {
+    pub section: String,
+    pub offset: u16,
+    pub kind: RelocKind,
+    pub symbol: String,
+}
+
+impl Object {
+    /*
+        Encodes an assembled relocatable program, turning the label
+        references it kept into relocations. Offsets are relative to the
+        section holding the word or label.
+    */
+    pub fn from_program(program: &Program, isa: &Isa) -> Object {
+        let mut sections: Vec<Section> = program.sections
+            .iter()
+            .map(|placed| Section { name: placed.name.clone(), size: placed.size, words: vec![] })
+            .collect();
+        let mut relocations: Vec<Relocation> = Vec::new();
+        for (addr, instr) in program.instrs.iter() {
+            let idx = program.sections
+                .iter()
+                .position(|placed| placed.contains(*addr))
+                .unwrap_or_else(|| panic!("{} at {:#X} lies outside every section", instr, addr));
+            let offset = addr - program.sections[idx].base;
+            let encoded = match unresolved(instr) {
+                Some((placeholder, kind, symbol)) => {
+                    relocations.push(Relocation { section: sections[idx].name.clone(), offset, kind, symbol });
+                    instr_to_words(&placeholder, isa)
+                },
+                None => instr_to_words(instr, isa),
+            };
+            for (i, word) in encoded.into_iter().enumerate() {
+                sections[idx].words.push((offset + i as u16, word));
+            }
+        }
+        let mut symbols: Vec<Symbol> = Vec::new();
+        for placed in program.sections.iter() {
+            let mut labels: Vec<Symbol> = placed.labels
+                .iter()
+                .map(|name| Symbol {
+                    name: name.clone(),
+                    section: placed.name.clone(),
+                    offset: program.symbols[name] - placed.base,
+                    global: program.globals.contains(name),
+                })
+                .collect();
+            labels.sort_by(|a, b| (a.offset, &a.name).cmp(&(b.offset, &b.name)));
+            symbols.extend(labels);
+        }
+        Object {
+            sections,
+            symbols,
+            externs: program.externs.clone(),
+            relocations,
+        }
+    }
+
+    pub fn parse(text: &str) -> Object {
+        let mut object = Object { sections: vec![], symbols: vec![], externs: vec![], relocations: vec![] };
+        let mut lines = text.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+        match lines.next().map(|(_, line)| line.split_whitespace().collect::<Vec<_>>()) {
+            Some(ref parts) if parts.len() == 2 && parts[0] == "object" && parts[1] == VERSION.to_string() => (),
+            _ => panic!("Not a version {} object file", VERSION),
+        }
+        for (idx, line) in lines {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let expect = |count: usize| if parts.len() < count {
+                panic!("Expected {} fields in {} record on line {}", count, parts[0], idx + 1);
+            };
+            match parts[0] {
+                "section" => {
+                    expect(3);
+                    object.sections.push(Section {
+                        name: parts[1].to_string(),
+                        size: parse_hex(parts[2], idx),
+                        words: vec![],
+                    });
+                },
+                "data" => {
+                    expect(4);
+                    let start = parse_hex(parts[2], idx);
+                    let section = object.sections
+                        .iter_mut()
+                        .find(|s| s.name == parts[1])
+                        .unwrap_or_else(|| panic!("Data for undeclared section {} on line {}", parts[1], idx + 1));
+                    for (i, word) in parts[3..].iter().enumerate() {
+                        section.words.push((start + i as u16, parse_hex(word, idx)));
+                    }
+                },
+                "symbol" => {
+                    expect(5);
+                    object.symbols.push(Symbol {
+                        name: parts[1].to_string(),
+                        section: parts[2].to_string(),
+                        offset: parse_hex(parts[3], idx),
+                        global: match parts[4] {
+                            "global" => true,
+                            "local" => false,
+                            other => panic!("Unrecognized symbol binding {} on line {}", other, idx + 1),
+                        },
+                    });
+                },
+                "extern" => {
+                    expect(2);
+                    object.externs.push(parts[1].to_string());
+                },
+                "reloc" => {
+                    expect(5);
+                    object.relocations.push(Relocation {
+                        section: parts[1].to_string(),
+                        offset: parse_hex(parts[2], idx),
+                        kind: match parts[3] {
+                            "jump" => RelocKind::Jump,
+                            "branch" => RelocKind::Branch,
+                            "hi" => RelocKind::Hi,
+                            "lo" => RelocKind::Lo,
+                            other => panic!("Unrecognized relocation kind {} on line {}", other, idx + 1),
+                        },
+                        symbol: parts[4].to_string(),
+                    });
+                },
+                other => panic!("Unrecognized object record {} on line {}", other, idx + 1),
+            }
+        }
+        object
+    }
+}
+
+/*
+    For an instruction still referring to a label, the instruction with
+    that field zeroed and the relocation that fills it in.
+*/
+fn unresolved(instr: &IRInstruction) -> Option<(IRInstruction, RelocKind, String)> {
+    match instr {
+        IRInstruction::JTypeLabel(jtype, label) =>
+            Some((IRInstruction::JTypeAddr(jtype.clone(), 0), RelocKind::Jump, label.clone())),
+        IRInstruction::ITypeLabel(itype, reg1, reg2, label) =>
+            Some((IRInstruction::IType(itype.clone(), reg1.clone(), reg2.clone(), 0), RelocKind::Branch, label.clone())),
+        IRInstruction::ITypeWideLabel(itype, reg, half, label) => {
+            let kind = match half {
+                Half::Hi => RelocKind::Hi,
+                Half::Lo => RelocKind::Lo,
+            };
+            Some((IRInstruction::ITypeWide(itype.clone(), reg.clone(), 0), kind, label.clone()))
+        },
+        _ => None,
+    }
+}
+
+fn parse_hex(expr: &str, idx: usize) -> u16 {
+    u16::from_str_radix(expr, 16)
+        .unwrap_or_else(|_| panic!("Error parsing hexadecimal number {} on line {}", expr, idx + 1))
+}
+
+impl core::fmt::Display for RelocKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        use self::RelocKind::*;
+        match self {
+            Jump => write!(f, "jump"),
+            Branch => write!(f, "branch"),
+            Hi => write!(f, "hi"),
+            Lo => write!(f, "lo"),
+        }
+    }
+}
+
+impl core::fmt::Display for Object {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(f, "object {}", VERSION)?;
+        for section in self.sections.iter() {
+            writeln!(f, "section {} {:X}", section.name, section.size)?;
+            // one record per run of consecutive words
+            let mut run: Vec<(u16, u16)> = Vec::new();
+            for (offset, word) in section.words.iter() {
+                let contiguous = run.last().is_none_or(|(last, _)| last + 1 == *offset);
+                if !contiguous || run.len() == WORDS_PER_RECORD {
+                    write_data(f, &section.name, &run)?;
+                    run.clear();
+                }
+                run.push((*offset, *word));
+            }
+            write_data(f, &section.name, &run)?;
+        }
+        for symbol in self.symbols.iter() {
+            let binding = if symbol.global { "global" } else { "local" };
+            writeln!(f, "symbol {} {} {:X} {}", symbol.name, symbol.section, symbol.offset, binding)?;
+        }
+        for name in self.externs.iter() {
+            writeln!(f, "extern {}", name)?;
+        }
+        for reloc in self.relocations.iter() {
+            writeln!(f, "reloc {} {:X} {} {}", reloc.section, reloc.offset, reloc.kind, reloc.symbol)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_data(f: &mut core::fmt::Formatter, section: &str, run: &[(u16, u16)]) -> core::fmt::Result {
+    if run.is_empty() {
+        return Ok(());
+    }
+    let words: Vec<String> = run.iter().map(|(_, word)| format!("{:X}", word)).collect();
+    writeln!(f, "data {} {:X} {}", section, run[0].0, words.join(" "))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, assembler::Options, isa::Isa, object::*};
+
+    fn object(text: &str) -> Object {
+        let options = Options { object: true, ..Options::default() };
+        Object::from_program(&assemble(text, &options), &Isa::default())
+    }
+
+    #[test]
+    fn write_object() {
+        let text = ".globl main\n.extern msg\n.extern print\n.extern elsewhere\nmain:\nlli $a0, %lo(msg)\nlui $a0, %hi(msg)\njal print\nbeq $v0, $0, main\nbne $v0, $0, elsewhere\n.space 2\ndone:\n";
+        assert_eq!(object(text).to_string(), "\
+object 1
+section .text 7
+data .text 0 A800 B800 2000 543C 6400
+symbol main .text 0 global
+symbol done .text 7 local
+extern msg
+extern print
+extern elsewhere
+reloc .text 0 lo msg
+reloc .text 1 hi msg
+reloc .text 2 jump print
+reloc .text 4 branch elsewhere
+");
+    }
+
+    #[test]
+    fn parse_object_round_trip() {
+        let written = object(".globl foo\n.extern bar\n.word 0x1234\nfoo:\nbeq $v0, $0, bar\njmp foo\n.asciiz \"ab\"\n");
+        assert_eq!(Object::parse(&written.to_string()), written);
+    }
+
+    #[test]
+    fn write_object_sections() {
+        let text = ".data\ncount:\n.word 3\n.text\nmain:\nlli $a0, %lo(count)\nlui $a0, %hi(count)\nbeq $0, $0, main\nbeq $0, $0, count\n.bss\nbuf:\n.space 4\n";
+        assert_eq!(object(text).to_string(), "\
+object 1
+section .text 4
+data .text 0 A800 B800 503D 5000
+section .data 1
+data .data 0 3
+section .bss 4
+symbol main .text 0 local
+symbol count .data 0 local
+symbol buf .bss 0 local
+reloc .text 0 lo count
+reloc .text 1 hi count
+reloc .text 3 branch count
+");
+    }
+
+    #[test]
+    #[should_panic(expected = "Not a version 1 object file")]
+    fn parse_rejects_other_files() {
+        Object::parse("DEPTH = 16384;\n");
+    }
+}
@@ -0,0 +1,647 @@
+/*
+    Output backends. Every format is written by an ImageWriter from the
+    same resolved memory image: the words of the program by word address,
+    along with the source line each run of words came from, which formats
+    with comments can show, and the program's symbols. Programs using the
+    crate as a library add formats of their own with `register`.
+
+    Formats addressing bytes rather than words (Intel HEX, S-records, raw
+    binary) put word `a` at byte address `2a`, most significant byte first
+    unless a little-endian byte order is asked for. Formats without
+    addresses (COE, Lattice .mem, binary, C and Rust arrays) hold every
+    word from address 0, filling gaps with the padding word.
+*/
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use memory::MEMORY_WORDS;
+
+const IHEX_RECORD_BYTES: usize = 16;
+const SREC_RECORD_BYTES: usize = 16;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub words: Vec<(u16, u16)>, // word address and word, by address
+    pub source: Vec<(u16, String)>, // address of the first word of a source line and its text
+    pub symbols: Vec<(String, u16)>, // by address
+}
+
+pub trait ImageWriter {
+    /*
+        The name that selects the writer with --format.
+    */
+    fn name(&self) -> &'static str;
+
+    /*
+        Appended to the input file's name when no output file is given.
+    */
+    fn extension(&self) -> &'static str;
+
+    fn write(&self, image: &Image, options: &WriteOptions) -> Vec<u8>;
+}
+
+pub struct Mif;
+pub struct IntelHex;
+pub struct SRecord;
+pub struct ReadMemH; // Verilog $readmemh
+pub struct ReadMemB; // Verilog $readmemb
+pub struct Coe; // Xilinx block RAM initialization
+pub struct LatticeMem;
+pub struct Binary;
+pub struct CArray;
+pub struct RustArray;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ByteOrder {
+    #[default]
+    Big,
+    Little,
+}
+
+#[derive(Debug, Clone)]
+pub struct WriteOptions {
+    pub byte_order: ByteOrder, // of words in byte-addressed formats
+    pub comments: bool, // add source lines to formats where they are optional
+    pub pad: u16, // fills gaps in formats without addresses
+    pub depth: u32, // words of memory, for formats that declare it
+}
+
+impl Default for WriteOptions {
+    fn default() -> WriteOptions {
+        WriteOptions {
+            byte_order: ByteOrder::default(),
+            comments: false,
+            pad: 0,
+            depth: MEMORY_WORDS,
+        }
+    }
+}
+
+type MakeWriter = fn() -> Box<dyn ImageWriter>;
+
+static REGISTERED: Mutex<Vec<MakeWriter>> = Mutex::new(Vec::new());
+
+/*
+    Every writer, the built-in ones first with MIF as the default, then
+    the registered ones in the order they were registered.
+*/
+pub fn writers() -> Vec<Box<dyn ImageWriter>> {
+    let mut writers: Vec<Box<dyn ImageWriter>> = vec![
+        Box::new(Mif),
+        Box::new(IntelHex),
+        Box::new(SRecord),
+        Box::new(ReadMemH),
+        Box::new(ReadMemB),
+        Box::new(Coe),
+        Box::new(LatticeMem),
+        Box::new(Binary),
+        Box::new(CArray),
+        Box::new(RustArray),
+    ];
+    writers.extend(REGISTERED.lock().unwrap().iter().map(|make| make()));
+    writers
+}
+
+/*
+    Makes the writer built by `make` selectable with --format. Panics if a
+    writer of that name already exists.
+*/
+pub fn register(make: MakeWriter) {
+    let name = make().name();
+    if writers().iter().any(|writer| writer.name() == name) {
+        panic!("Output format {} is already defined", name);
+    }
+    REGISTERED.lock().unwrap().push(make);
+}
+
+pub fn writer(name: &str) -> Box<dyn ImageWriter> {
+    let names: Vec<&str> = writers().iter().map(|writer| writer.name()).collect();
+    writers()
+        .into_iter()
+        .find(|writer| writer.name() == name)
+        .unwrap_or_else(|| panic!("Unrecognized output format {} (expected one of {})", name, names.join(", ")))
+}
+
+impl ByteOrder {
+    pub fn parse(name: &str) -> ByteOrder {
+        match name {
+            "big" => ByteOrder::Big,
+            "little" => ByteOrder::Little,
+            other => panic!("Unrecognized byte order {} (expected big or little)", other),
+        }
+    }
+
+    fn split(&self, word: u16) -> [u8; 2] {
+        match self {
+            ByteOrder::Big => [(word >> 8) as u8, word as u8],
+            ByteOrder::Little => [word as u8, (word >> 8) as u8],
+        }
+    }
+}
+
+impl Image {
+    /*
+        An image without source lines, such as a linked one.
+    */
+    pub fn from_words(words: Vec<(u16, u16)>, symbols: &HashMap<String, u16>) -> Image {
+        Image { words, source: vec![], symbols: by_address(symbols) }
+    }
+
+    /*
+        Every word from address 0 to the last one, the padding word where
+        the image has none.
+    */
+    fn dense(&self, pad: u16) -> Vec<u16> {
+        let len = self.words.last().map_or(0, |(addr, _)| *addr as usize + 1);
+        let mut words = vec![pad; len];
+        for (addr, word) in self.words.iter() {
+            words[*addr as usize] = *word;
+        }
+        words
+    }
+
+    fn source_at(&self, addr: u16) -> Option<&String> {
+        self.source.iter().find(|(start, _)| *start == addr).map(|(_, line)| line)
+    }
+
+    /*
+        The image's bytes by byte address.
+    */
+    fn bytes(&self, order: ByteOrder) -> Vec<(u32, u8)> {
+        self.words
+            .iter()
+            .flat_map(|(addr, word)| {
+                let [first, second] = order.split(*word);
+                vec![((*addr as u32) << 1, first), (((*addr as u32) << 1) + 1, second)]
+            })
+            .collect()
+    }
+}
+
+pub fn by_address(symbols: &HashMap<String, u16>) -> Vec<(String, u16)> {
+    let mut sorted: Vec<(String, u16)> = symbols.iter().map(|(name, addr)| (name.clone(), *addr)).collect();
+    sorted.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    sorted
+}
+
+macro_rules! text_writer {
+    ($writer:ident, $name:expr, $extension:expr, |$image:ident, $options:ident| $text:expr) => {
+        impl ImageWriter for $writer {
+            fn name(&self) -> &'static str {
+                $name
+            }
+
+            fn extension(&self) -> &'static str {
+                $extension
+            }
+
+            fn write(&self, $image: &Image, $options: &WriteOptions) -> Vec<u8> {
+                $text.into_bytes()
+            }
+        }
+    };
+}
+
+text_writer!(Mif, "mif", ".mif", |image, options| to_mif(image, options.depth));
+text_writer!(IntelHex, "ihex", ".hex", |image, options| to_ihex(image, options.byte_order));
+text_writer!(SRecord, "srec", ".srec", |image, options| to_srec(image, options.byte_order));
+text_writer!(ReadMemH, "readmemh", ".memh", |image, options| to_readmem(image, 16, options.comments));
+text_writer!(ReadMemB, "readmemb", ".memb", |image, options| to_readmem(image, 2, options.comments));
+text_writer!(Coe, "coe", ".coe", |image, options| to_coe(image, options.pad));
+text_writer!(LatticeMem, "mem", ".mem", |image, options| to_lattice_mem(image, options.pad));
+text_writer!(CArray, "c", ".h", |image, options| to_c(image, options.pad));
+text_writer!(RustArray, "rust", ".rs", |image, options| to_rust(image, options.pad));
+
+impl ImageWriter for Binary {
+    fn name(&self) -> &'static str {
+        "bin"
+    }
+
+    fn extension(&self) -> &'static str {
+        ".bin"
+    }
+
+    fn write(&self, image: &Image, options: &WriteOptions) -> Vec<u8> {
+        image
+            .dense(options.pad)
+            .iter()
+            .flat_map(|word| options.byte_order.split(*word).to_vec())
+            .collect()
+    }
+}
+
+/*
+    One line per source line, holding every word it assembled to. Words
+    from no source line get a line each.
+*/
+fn to_mif(image: &Image, depth: u32) -> String {
+    let mut text = format!(
+"DEPTH = {};                -- The size of memory in words
+WIDTH = 16;                   -- The size of data in bits
+ADDRESS_RADIX = HEX;          -- The radix for address values
+DATA_RADIX = HEX;             -- The radix for data values
+CONTENT                       -- start of (address : data pairs)
+BEGIN\n", depth);
+    let mut lines: Vec<(u16, Vec<String>, Option<&String>)> = Vec::new();
+    for (addr, word) in image.words.iter() {
+        let source = image.source_at(*addr);
+        let continues = match lines.last() {
+            Some((start, words, _)) => source.is_none() && *start as usize + words.len() == *addr as usize,
+            None => false,
+        };
+        if continues && !image.source.is_empty() {
+            lines.last_mut().unwrap().1.push(format!("{:X}", word));
+        } else {
+            lines.push((*addr, vec![format!("{:X}", word)], source));
+        }
+    }
+    for (addr, words, source) in lines.iter() {
+        match source {
+            Some(line) => text += &format!("{:X} : {}; -- {}\n", addr, words.join(" "), line),
+            None => text += &format!("{:X} : {};\n", addr, words.join(" ")),
+        }
+    }
+    text + "END;\n"
+}
+
+/*
+    Data records of at most 16 bytes, with an extended linear address
+    record whenever the upper 16 bits of the byte address change.
+*/
+fn to_ihex(image: &Image, order: ByteOrder) -> String {
+    let mut text = String::new();
+    let mut upper: u32 = 0;
+    for (start, data) in runs(&image.bytes(order), IHEX_RECORD_BYTES, true) {
+        if start >> 16 != upper {
+            upper = start >> 16;
+            text += &ihex_record(0x04, 0, &[(upper >> 8) as u8, upper as u8]);
+        }
+        text += &ihex_record(0x00, start as u16, &data);
+    }
+    text + &ihex_record(0x01, 0, &[])
+}
+
+fn ihex_record(kind: u8, addr: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8, (addr >> 8) as u8, addr as u8, kind];
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+    format!(":{}\n", hex(&bytes))
+}
+
+/*
+    S1 records with 16-bit addresses while the image fits them, S2
+    records with 24-bit addresses otherwise, closed by a record count
+    and the matching termination record.
+*/
+fn to_srec(image: &Image, order: ByteOrder) -> String {
+    let bytes = image.bytes(order);
+    let wide = bytes.last().is_some_and(|(addr, _)| *addr > 0xFFFF);
+    let (data_kind, end_kind, addr_len) = if wide { (2, 8, 3) } else { (1, 9, 2) };
+    let mut text = srec_record(0, 0, 2, b"HDR");
+    let mut count: u32 = 0;
+    for (start, data) in runs(&bytes, SREC_RECORD_BYTES, false) {
+        text += &srec_record(data_kind, start, addr_len, &data);
+        count += 1;
+    }
+    if count <= 0xFFFF {
+        text += &srec_record(5, count, 2, &[]);
+    }
+    text + &srec_record(end_kind, 0, addr_len, &[])
+}
+
+fn srec_record(kind: u8, addr: u32, addr_len: usize, data: &[u8]) -> String {
+    let mut bytes = vec![(addr_len + data.len() + 1) as u8];
+    for i in (0..addr_len).rev() {
+        bytes.push((addr >> (8 * i)) as u8);
+    }
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+    bytes.push(!sum);
+    format!("S{}{}\n", kind, hex(&bytes))
+}
+
+/*
+    One word per line for $readmemh or $readmemb, with an @address line
+    wherever the words stop being consecutive, as after .org.
+*/
+fn to_readmem(image: &Image, radix: u32, comments: bool) -> String {
+    let mut text = String::new();
+    let mut next: u32 = 0;
+    for (addr, word) in image.words.iter() {
+        if *addr as u32 != next {
+            text += &format!("@{:X}\n", addr);
+        }
+        next = *addr as u32 + 1;
+        text += &match radix {
+            2 => format!("{:016b}", word),
+            _ => format!("{:04X}", word),
+        };
+        match image.source_at(*addr) {
+            Some(line) if comments => text += &format!(" // {}\n", line),
+            _ => text += "\n",
+        }
+    }
+    text
+}
+
+fn to_coe(image: &Image, pad: u16) -> String {
+    let words: Vec<String> = image.dense(pad).iter().map(|word| format!("{:04X}", word)).collect();
+    format!("memory_initialization_radix=16;\nmemory_initialization_vector=\n{};\n", words.join(",\n"))
+}
+
+fn to_lattice_mem(image: &Image, pad: u16) -> String {
+    let words = image.dense(pad);
+    let mut text = format!("#Format=Hex\n#Depth={}\n#Width=16\n#AddrRadix=3\n#DataRadix=3\n#Data\n", words.len());
+    for word in words.iter() {
+        text += &format!("{:04X}\n", word);
+    }
+    text
+}
+
+/*
+    A header defining the words as an array, with a #define giving the
+    word address of each symbol.
+*/
+fn to_c(image: &Image, pad: u16) -> String {
+    let words = image.dense(pad);
+    let mut text = String::from("#include <stdint.h>\n\n");
+    for (name, addr) in symbol_constants(image) {
+        text += &format!("#define {} 0x{:04X}u\n", name, addr);
+    }
+    if !image.symbols.is_empty() {
+        text += "\n";
+    }
+    text += &format!("const uint16_t program[{}] = {{\n{}}};\n", words.len(), array_rows(&words));
+    text
+}
+
+/*
+    A module defining the words as a static array, with a constant giving
+    the word address of each symbol, named as in the C header.
+*/
+fn to_rust(image: &Image, pad: u16) -> String {
+    let words = image.dense(pad);
+    let mut text = String::new();
+    for (name, addr) in symbol_constants(image) {
+        text += &format!("pub const {}: u16 = 0x{:04X};\n", name, addr);
+    }
+    if !image.symbols.is_empty() {
+        text += "\n";
+    }
+    text += &format!("pub static PROGRAM: [u16; {}] = [\n{}];\n", words.len(), array_rows(&words));
+    text
+}
+
+const ARRAY_ROW_WORDS: usize = 8;
+
+fn array_rows(words: &[u16]) -> String {
+    words
+        .chunks(ARRAY_ROW_WORDS)
+        .map(|row| {
+            let row: Vec<String> = row.iter().map(|word| format!("0x{:04X},", word)).collect();
+            format!("    {}\n", row.join(" "))
+        })
+        .collect()
+}
+
+/*
+    Each symbol as an upper-case identifier prefixed with PROGRAM_, so none
+    clashes with the array or a keyword. Panics if two symbols map to the
+    same identifier.
+*/
+fn symbol_constants(image: &Image) -> Vec<(String, u16)> {
+    let mut constants: Vec<(String, u16)> = Vec::new();
+    for (symbol, addr) in image.symbols.iter() {
+        let name: String = symbol
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+            .collect();
+        let name = format!("PROGRAM_{}", name);
+        if let Some(i) = constants.iter().position(|(taken, _)| *taken == name) {
+            panic!("Symbols {} and {} both become the constant {}", image.symbols[i].0, symbol, name);
+        }
+        constants.push((name, *addr));
+    }
+    constants
+}
+
+/*
+    Splits bytes into records of consecutive addresses, optionally never
+    crossing a 64K boundary.
+*/
+fn runs(bytes: &[(u32, u8)], max_len: usize, split_64k: bool) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (addr, byte) in bytes.iter() {
+        let continues = match runs.last() {
+            Some((start, data)) => start + data.len() as u32 == *addr
+                && data.len() < max_len
+                && !(split_64k && addr & 0xFFFF == 0),
+            None => false,
+        };
+        if continues {
+            runs.last_mut().unwrap().1.push(*byte);
+        } else {
+            runs.push((*addr, vec![*byte]));
+        }
+    }
+    runs
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::output::*;
+
+    fn write_text(image: &Image, format: &str, options: &WriteOptions) -> String {
+        String::from_utf8(writer(format).write(image, options)).unwrap()
+    }
+
+    fn image() -> Image {
+        Image {
+            words: vec![(0, 0x2005), (1, 0x68), (2, 0x69), (0x10, 0xA808)],
+            source: vec![(0, String::from("jal main")), (1, String::from(".asciiz hi"))],
+            symbols: vec![(String::from("main"), 0), (String::from("msg"), 1)],
+        }
+    }
+
+    #[test]
+    fn write_mif() {
+        assert_eq!(write_text(&image(), "mif", &WriteOptions::default()), "\
+DEPTH = 16384;                -- The size of memory in words
+WIDTH = 16;                   -- The size of data in bits
+ADDRESS_RADIX = HEX;          -- The radix for address values
+DATA_RADIX = HEX;             -- The radix for data values
+CONTENT                       -- start of (address : data pairs)
+BEGIN
+0 : 2005; -- jal main
+1 : 68 69; -- .asciiz hi
+10 : A808;
+END;
+");
+    }
+
+    #[test]
+    fn write_mif_with_layout_depth() {
+        let options = WriteOptions { depth: 0x10000, ..WriteOptions::default() };
+        assert!(write_text(&image(), "mif", &options).starts_with("DEPTH = 65536; "));
+    }
+
+    #[test]
+    fn write_ihex() {
+        assert_eq!(write_text(&image(), "ihex", &WriteOptions::default()), "\
+:0600000020050068006904
+:02002000A8082E
+:00000001FF
+");
+        assert!(write_text(&image(), "ihex", &WriteOptions { byte_order: ByteOrder::Little, ..WriteOptions::default() }).starts_with(":06000000052068006900"));
+    }
+
+    #[test]
+    fn write_ihex_extended_address() {
+        let image = Image::from_words(vec![(0x7FFF, 0x1234), (0x8000, 0x5678)], &HashMap::new());
+        assert_eq!(write_text(&image, "ihex", &WriteOptions::default()), "\
+:02FFFE001234BB
+:020000040001F9
+:02000000567830
+:00000001FF
+");
+    }
+
+    #[test]
+    fn write_srec() {
+        assert_eq!(write_text(&image(), "srec", &WriteOptions::default()), "\
+S00600004844521B
+S109000020050068006900
+S1050020A8082A
+S5030002FA
+S9030000FC
+");
+        let wide = Image::from_words(vec![(0x8000, 0x5678)], &HashMap::new());
+        assert_eq!(write_text(&wide, "srec", &WriteOptions::default()), "\
+S00600004844521B
+S20601000056782A
+S5030001FB
+S804000000FB
+");
+    }
+
+    #[test]
+    fn write_readmem() {
+        let comments = WriteOptions { comments: true, ..WriteOptions::default() };
+        assert_eq!(write_text(&image(), "readmemh", &comments), "\
+2005 // jal main
+0068 // .asciiz hi
+0069
+@10
+A808
+");
+        let image = Image::from_words(vec![(2, 0x8E00)], &HashMap::new());
+        assert_eq!(write_text(&image, "readmemb", &WriteOptions::default()), "@2\n1000111000000000\n");
+    }
+
+    #[test]
+    fn write_coe_and_lattice_mem() {
+        let image = Image::from_words(vec![(0, 0x2005), (2, 0x68)], &HashMap::new());
+        assert_eq!(write_text(&image, "coe", &WriteOptions::default()), "\
+memory_initialization_radix=16;
+memory_initialization_vector=
+2005,
+0000,
+0068;
+");
+        assert_eq!(write_text(&image, "mem", &WriteOptions::default()), "\
+#Format=Hex
+#Depth=3
+#Width=16
+#AddrRadix=3
+#DataRadix=3
+#Data
+2005
+0000
+0068
+");
+    }
+
+    #[test]
+    fn write_bin() {
+        let image = Image::from_words(vec![(0, 0x2005), (2, 0x68)], &HashMap::new());
+        let little = WriteOptions { byte_order: ByteOrder::Little, pad: 0xFFFF, ..WriteOptions::default() };
+        assert_eq!(Binary.write(&image, &WriteOptions::default()), vec![0x20, 0x05, 0, 0, 0, 0x68]);
+        assert_eq!(Binary.write(&image, &little), vec![0x05, 0x20, 0xFF, 0xFF, 0x68, 0]);
+    }
+
+    #[test]
+    fn write_c_and_rust() {
+        assert_eq!(write_text(&image(), "c", &WriteOptions::default()), "\
+#include <stdint.h>
+
+#define PROGRAM_MAIN 0x0000u
+#define PROGRAM_MSG 0x0001u
+
+const uint16_t program[17] = {
+    0x2005, 0x0068, 0x0069, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+    0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+    0xA808,
+};
+");
+        assert_eq!(write_text(&image(), "rust", &WriteOptions::default()), "\
+pub const PROGRAM_MAIN: u16 = 0x0000;
+pub const PROGRAM_MSG: u16 = 0x0001;
+
+pub static PROGRAM: [u16; 17] = [
+    0x2005, 0x0068, 0x0069, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+    0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000, 0x0000,
+    0xA808,
+];
+");
+    }
+
+    #[test]
+    #[should_panic(expected = "Symbols loop and Loop both become the constant PROGRAM_LOOP")]
+    fn write_rust_rejects_clashing_symbols() {
+        let image = Image {
+            symbols: vec![(String::from("loop"), 0), (String::from("Loop"), 1)],
+            ..image()
+        };
+        write_text(&image, "rust", &WriteOptions::default());
+    }
+
+    struct WordCount;
+
+    impl ImageWriter for WordCount {
+        fn name(&self) -> &'static str {
+            "count"
+        }
+
+        fn extension(&self) -> &'static str {
+            ".txt"
+        }
+
+        fn write(&self, image: &Image, _options: &WriteOptions) -> Vec<u8> {
+            format!("{} words, {} symbols\n", image.words.len(), image.symbols.len()).into_bytes()
+        }
+    }
+
+    #[test]
+    fn custom_writer() {
+        register(|| Box::new(WordCount));
+        assert_eq!(writer("count").write(&image(), &WriteOptions::default()), b"4 words, 2 symbols\n".to_vec());
+        assert_eq!(writers()[0].name(), "mif");
+    }
+
+    #[test]
+    #[should_panic(expected = "Output format mif is already defined")]
+    fn register_rejects_taken_names() {
+        register(|| Box::new(Mif));
+    }
+
+    #[test]
+    #[should_panic(expected = "Unrecognized output format elf (expected one of mif, ihex, srec, readmemh, readmemb, coe, mem, bin, c, rust")]
+    fn unknown_format() {
+        writer("elf");
+    }
+}
@@ -0,0 +1,211 @@
+use assembler::expand_li;
+use ir::Directive;
+use ir::IType;
+use ir::JType;
+use ir::Pseudo;
+use ir::IRInstruction;
+
+/*
+    Peephole pass over the parsed lines of a program:
+    - drops `addi $x, $x, 0`
+    - drops a `li` whose register the next `li` overwrites
+    - folds `li $x, a` followed by `addi $x, $x, b` into `li $x, a+b`
+    - drops a `jmp` to the instruction right after it
+    Rewrites only look past blank lines, so a label between two lines
+    (which a branch could land on) keeps them apart, and they leave alone
+    the lines a numeric branch offset counts words across, whose removal
+    would move its target. Repeats until nothing changes, as one rewrite
+    can expose another.
+*/
+pub fn optimize(lines: Vec<IRInstruction>) -> Vec<IRInstruction> {
+    let mut lines = lines;
+    loop {
+        let before = lines.len();
+        lines = optimize_once(lines);
+        if lines.len() == before {
+            return lines;
+        }
+    }
+}
+
+fn optimize_once(lines: Vec<IRInstruction>) -> Vec<IRInstruction> {
+    let mut out: Vec<IRInstruction> = Vec::new();
+    let mut removed: Vec<bool> = vec![false; lines.len()];
+    let pinned = pinned_lines(&lines);
+    for idx in 0..lines.len() {
+        if removed[idx] {
+            continue;
+        }
+        if pinned[idx] {
+            out.push(lines[idx].clone());
+            continue;
+        }
+        let next = next_line(&lines, idx).filter(|n| !pinned[*n]);
+        match &lines[idx] {
+            IRInstruction::IType(IType::AddI, reg1, reg2, 0) if reg1 == reg2 => continue,
+            IRInstruction::JTypeLabel(JType::Jmp, label) if falls_through_to(&lines, idx, label) => continue,
+            IRInstruction::Pseudo(Pseudo::LI(reg, val)) => {
+                match next.map(|n| (n, &lines[n])) {
+                    Some((_, IRInstruction::Pseudo(Pseudo::LI(next_reg, _)))) if next_reg == reg => continue,
+                    Some((n, IRInstruction::IType(IType::AddI, reg1, reg2, im))) if reg1 == reg && reg2 == reg => {
+                        removed[n] = true;
+                        out.push(IRInstruction::Pseudo(Pseudo::LI(reg.clone(), val.wrapping_add(*im as i16))));
+                        continue;
+                    },
+                    _ => (),
+                }
+            },
+            _ => (),
+        }
+        out.push(lines[idx].clone());
+    }
+    out
+}
+
+/*
+    The lines between a numeric branch and its target, counting the words
+    they take as laid out. Past an .org or a section switch the layout
+    decides where words go, so the count stops there.
+*/
+fn pinned_lines(lines: &[IRInstruction]) -> Vec<bool> {
+    let mut pinned = vec![false; lines.len()];
+    for (idx, line) in lines.iter().enumerate() {
+        let offset = match line {
+            IRInstruction::IType(IType::BEQ, _, _, offset) | IRInstruction::IType(IType::BNE, _, _, offset) => *offset as i32,
+            _ => continue,
+        };
+        // forward from the line after the branch, or back from the branch itself
+        let mut remaining = offset.abs();
+        let mut i = if offset > 0 { idx + 1 } else { idx };
+        while remaining > 0 && i < lines.len() {
+            pinned[i] = true;
+            match words(&lines[i]) {
+                Some(words) => remaining -= words as i32,
+                None => break,
+            }
+            if offset > 0 {
+                i += 1;
+            } else if i == 0 {
+                break;
+            } else {
+                i -= 1;
+            }
+        }
+    }
+    pinned
+}
+
+/*
+    Words a line takes, or None for lines that move the address counter
+    elsewhere.
+*/
+fn words(line: &IRInstruction) -> Option<u16> {
+    match line {
+        IRInstruction::Label(_) | IRInstruction::BlankLine => Some(0),
+        IRInstruction::Directive(dir) => match dir {
+            Directive::Asciiz(s) => Some(s.len() as u16),
+            Directive::Space(space) => Some(*space),
+            Directive::Byte(_) | Directive::Word(_) => Some(1),
+            Directive::Globl(_) | Directive::Extern(_) => Some(0),
+            Directive::Org(_) | Directive::Section(_) => None,
+        },
+        IRInstruction::Pseudo(Pseudo::LI(reg, val)) => Some(expand_li(0, reg, *val).len() as u16),
+        _ => Some(1),
+    }
+}
+
+/*
+    Index of the next line after idx that isn't blank.
+*/
+fn next_line(lines: &[IRInstruction], idx: usize) -> Option<usize> {
+    (idx + 1..lines.len()).find(|&i| lines[i] != IRInstruction::BlankLine)
+}
+
+/*
+    Whether the label names the address right after the line at idx,
+    i.e. only blank lines and labels lie in between.
+*/
+fn falls_through_to(lines: &[IRInstruction], idx: usize, label: &str) -> bool {
+    for line in lines[idx + 1..].iter() {
+        match line {
+            IRInstruction::BlankLine => (),
+            IRInstruction::Label(name) if name == label => return true,
+            IRInstruction::Label(_) => (),
+            _ => return false,
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, assembler::parse_line, assembler::Options, ir::*, peephole::optimize};
+
+    fn optimize_text(text: &str) -> Vec<IRInstruction> {
+        optimize(text.lines().map(parse_line).collect())
+            .into_iter()
+            .filter(|instr| *instr != IRInstruction::BlankLine)
+            .collect()
+    }
+
+    #[test]
+    fn remove_addi_zero() {
+        assert_eq!(optimize_text("addi $v0, $v0, 0\naddi $v0, $v1, 0"), vec![
+            IRInstruction::IType(IType::AddI, Register::V0, Register::V1, 0),
+        ]);
+    }
+
+    #[test]
+    fn merge_li() {
+        assert_eq!(optimize_text("li $v0, 1\n\nli $v0, 2\nli $v1, 3"), vec![
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 2)),
+            IRInstruction::Pseudo(Pseudo::LI(Register::V1, 3)),
+        ]);
+        // a label between them can be branched to
+        assert_eq!(optimize_text("li $v0, 1\nl:\nli $v0, 2"), vec![
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 1)),
+            IRInstruction::Label(String::from("l")),
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 2)),
+        ]);
+    }
+
+    #[test]
+    fn fold_li_addi() {
+        assert_eq!(optimize_text("li $v0, 0x7FFF\naddi $v0, $v0, 1\naddi $v0, $v0, -2"), vec![
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 0x7FFE)),
+        ]);
+        assert_eq!(optimize_text("li $v0, 5\naddi $v1, $v0, 1"), vec![
+            IRInstruction::Pseudo(Pseudo::LI(Register::V0, 5)),
+            IRInstruction::IType(IType::AddI, Register::V1, Register::V0, 1),
+        ]);
+    }
+
+    #[test]
+    fn keep_lines_numeric_branches_count() {
+        assert_eq!(optimize_text("beq $v0, $0, 2\naddi $v0, $v0, 0\naddi $v1, $v1, 0\naddi $a0, $a0, 0"), vec![
+            IRInstruction::IType(IType::BEQ, Register::V0, Register::Zero, 2),
+            IRInstruction::IType(IType::AddI, Register::V0, Register::V0, 0),
+            IRInstruction::IType(IType::AddI, Register::V1, Register::V1, 0),
+        ]);
+        assert_eq!(optimize_text("addi $v0, $v0, 0\nli $v1, 1\nli $v1, 2\nbne $v0, $0, -3"), vec![
+            IRInstruction::Pseudo(Pseudo::LI(Register::V1, 1)),
+            IRInstruction::Pseudo(Pseudo::LI(Register::V1, 2)),
+            IRInstruction::IType(IType::BNE, Register::V0, Register::Zero, -3),
+        ]);
+        // the branch still skips two words and lands past the li
+        let optimized = assemble("beq $v0, $0, 2\naddi $v0, $v0, 0\nli $v0, 1\nend:\n", &Options { optimize: true, ..Options::default() });
+        assert_eq!(optimized.instrs.len(), 3);
+        assert_eq!(optimized.symbols["end"], 3);
+    }
+
+    #[test]
+    fn drop_jump_to_next() {
+        assert_eq!(optimize_text("jmp a\nb:\n\na:\njmp b\njal c\nc:"), vec![
+            IRInstruction::Label(String::from("b")),
+            IRInstruction::Label(String::from("a")),
+            IRInstruction::JTypeLabel(JType::Jmp, String::from("b")),
+            IRInstruction::JTypeLabel(JType::Jal, String::from("c")),
+            IRInstruction::Label(String::from("c")),
+        ]);
+    }
+}
@@ -0,0 +1,320 @@
+/*
+    Reads existing images back into the memory image the writers use, so
+    that files from other tools can be disassembled, simulated or
+    converted. MIF files may use any radix, address ranges such as
+    `[10..1F] : 0;`, several words per address, and `--` or `% %`
+    comments. The `--` comment on a data line becomes the source line of
+    its words, as written by the MIF writer. Intel HEX files put word `a`
+    at byte address `2a`, in the given byte order, as do raw binary
+    images, which hold every word from address 0.
+*/
+
+use std::collections::BTreeMap;
+
+use output::ByteOrder;
+use output::Image;
+
+/*
+    Reads either format, telling them apart by Intel HEX's leading colon.
+*/
+pub fn read_image(text: &str, order: ByteOrder) -> Image {
+    if text.trim_start().starts_with(":") {
+        read_ihex(text, order)
+    } else {
+        read_mif(text)
+    }
+}
+
+pub fn read_mif(text: &str) -> Image {
+    let mut addr_radix: u32 = 16;
+    let mut data_radix: u32 = 16;
+    let mut depth: u32 = 1 << 16;
+    let mut width: u32 = 16;
+    let mut in_content = false;
+    let mut ended = false;
+    let mut words: BTreeMap<u16, u16> = BTreeMap::new();
+    let mut source: Vec<(u16, String)> = Vec::new();
+    let mut statement = String::new();
+    for (idx, (raw_line, (code, comment))) in text.lines().zip(split_comments(text)).enumerate() {
+        let pieces: Vec<&str> = code.split(";").collect();
+        for (i, piece) in pieces.iter().enumerate() {
+            statement.push_str(piece);
+            statement.push(' ');
+            if i + 1 == pieces.len() {
+                break; // not terminated yet
+            }
+            let mut rest = statement.trim();
+            if ended {
+                if !rest.is_empty() {
+                    panic!("Unexpected {} after END on line {}", rest, idx + 1);
+                }
+                statement.clear();
+                continue;
+            }
+            // CONTENT and BEGIN open the data without a semicolon
+            for keyword in ["CONTENT", "BEGIN"].iter() {
+                if rest.to_uppercase().starts_with(keyword) {
+                    rest = rest[keyword.len()..].trim();
+                    in_content = true;
+                }
+            }
+            if rest.to_uppercase() == "END" {
+                ended = true;
+            } else if !in_content {
+                let parts: Vec<&str> = rest.split("=").map(|s| s.trim()).collect();
+                if parts.len() != 2 {
+                    panic!("Expected `NAME = value;` on line {}: {}", idx + 1, raw_line);
+                }
+                match parts[0].to_uppercase().as_str() {
+                    "DEPTH" => depth = parse_mif_num(parts[1], 10, idx),
+                    "WIDTH" => width = parse_mif_num(parts[1], 10, idx),
+                    "ADDRESS_RADIX" => addr_radix = parse_radix(parts[1], idx),
+                    "DATA_RADIX" => data_radix = parse_radix(parts[1], idx),
+                    other => panic!("Unrecognized MIF setting {} on line {}", other, idx + 1),
+                }
+                if width > 16 {
+                    panic!("Words of {} bits do not fit the 16-bit memory image", width);
+                }
+            } else if !rest.is_empty() {
+                let parts: Vec<&str> = rest.splitn(2, ":").map(|s| s.trim()).collect();
+                if parts.len() != 2 {
+                    panic!("Expected `address : data;` on line {}: {}", idx + 1, raw_line);
+                }
+                let (first, last) = if parts[0].starts_with("[") && parts[0].ends_with("]") {
+                    let bounds: Vec<&str> = parts[0][1..parts[0].len()-1].split("..").collect();
+                    if bounds.len() != 2 {
+                        panic!("Expected an address range [first..last] on line {}", idx + 1);
+                    }
+                    (parse_mif_num(bounds[0], addr_radix, idx), Some(parse_mif_num(bounds[1], addr_radix, idx)))
+                } else {
+                    (parse_mif_num(parts[0], addr_radix, idx), None)
+                };
+                let data: Vec<u16> = parts[1]
+                    .split_whitespace()
+                    .map(|value| (parse_mif_num(value, data_radix, idx) & ((1u64 << width) - 1) as u32) as u16)
+                    .collect();
+                if data.is_empty() {
+                    panic!("Expected data for address {:X} on line {}", first, idx + 1);
+                }
+                // a range repeats its data, a single address takes it word by word
+                let last = last.unwrap_or(first + data.len() as u32 - 1);
+                if last >= depth {
+                    panic!("Address {:X} on line {} is beyond the depth of {} words", last, idx + 1, depth);
+                }
+                for (i, addr) in (first..=last).enumerate() {
+                    words.insert(addr as u16, data[i % data.len()]);
+                }
+                if !comment.is_empty() {
+                    source.push((first as u16, comment.to_string()));
+                }
+            }
+            statement.clear();
+        }
+    }
+    if !ended {
+        panic!("MIF file has no END");
+    }
+    Image { words: words.into_iter().collect(), source, symbols: vec![] }
+}
+
+pub fn read_ihex(text: &str, order: ByteOrder) -> Image {
+    let mut bytes: BTreeMap<u32, u8> = BTreeMap::new();
+    let mut base: u32 = 0;
+    for (idx, raw_line) in text.lines().enumerate() {
+        let line = raw_line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if !line.starts_with(":") || line.len() % 2 == 0 {
+            panic!("Expected an Intel HEX record on line {}: {}", idx + 1, raw_line);
+        }
+        let record: Vec<u8> = (1..line.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&line[i..i + 2], 16)
+                .unwrap_or_else(|_| panic!("Error parsing hexadecimal byte on line {}", idx + 1)))
+            .collect();
+        if record.len() < 5 || record.len() != record[0] as usize + 5 {
+            panic!("Record length does not match its byte count on line {}", idx + 1);
+        }
+        if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+            panic!("Bad checksum on line {}", idx + 1);
+        }
+        let addr = ((record[1] as u32) << 8) | record[2] as u32;
+        let data = &record[4..record.len() - 1];
+        match record[3] {
+            0x00 => for (i, byte) in data.iter().enumerate() {
+                bytes.insert(base + addr + i as u32, *byte);
+            },
+            0x01 => break,
+            0x02 => base = (((data[0] as u32) << 8) | data[1] as u32) << 4,
+            0x04 => base = (((data[0] as u32) << 8) | data[1] as u32) << 16,
+            0x03 | 0x05 => (), // start addresses
+            other => panic!("Unrecognized record type {:02X} on line {}", other, idx + 1),
+        }
+    }
+    let mut words: BTreeMap<u16, u16> = BTreeMap::new();
+    for (addr, byte) in bytes.iter() {
+        let word = words.entry((addr >> 1) as u16).or_insert(0);
+        let high = match order {
+            ByteOrder::Big => addr & 1 == 0,
+            ByteOrder::Little => addr & 1 == 1,
+        };
+        *word |= if high { (*byte as u16) << 8 } else { *byte as u16 };
+    }
+    Image { words: words.into_iter().collect(), source: vec![], symbols: vec![] }
+}
+
+/*
+    Reads a raw binary image, such as the bin writer's, two bytes a word.
+*/
+pub fn read_binary(bytes: &[u8], order: ByteOrder) -> Image {
+    if !bytes.len().is_multiple_of(2) {
+        panic!("Binary image has an odd number of bytes ({})", bytes.len());
+    }
+    if bytes.len() > 2 << 16 {
+        panic!("Binary image of {} bytes does not fit in memory", bytes.len());
+    }
+    let words = bytes
+        .chunks(2)
+        .enumerate()
+        .map(|(addr, pair)| {
+            let word = match order {
+                ByteOrder::Big => ((pair[0] as u16) << 8) | pair[1] as u16,
+                ByteOrder::Little => ((pair[1] as u16) << 8) | pair[0] as u16,
+            };
+            (addr as u16, word)
+        })
+        .collect();
+    Image { words, source: vec![], symbols: vec![] }
+}
+
+/*
+    Each line's code without comments, and the text of its `--` comment.
+    `%` comments may span lines, and a marker inside either kind of
+    comment doesn't start the other, so `-- 50%` opens no block comment.
+*/
+fn split_comments(text: &str) -> Vec<(String, String)> {
+    let mut lines: Vec<(String, String)> = Vec::new();
+    let mut in_block = false;
+    for line in text.lines() {
+        let mut code = String::new();
+        let mut comment = "";
+        for (i, c) in line.char_indices() {
+            if c == '%' {
+                in_block = !in_block;
+            } else if in_block {
+                continue;
+            } else if line[i..].starts_with("--") {
+                comment = line[i + 2..].trim();
+                break;
+            } else {
+                code.push(c);
+            }
+        }
+        lines.push((code, comment.to_string()));
+    }
+    lines
+}
+
+fn parse_radix(name: &str, idx: usize) -> u32 {
+    match name.to_uppercase().as_str() {
+        "HEX" => 16,
+        "BIN" => 2,
+        "OCT" => 8,
+        "DEC" | "UNS" => 10,
+        other => panic!("Unrecognized radix {} on line {}", other, idx + 1),
+    }
+}
+
+fn parse_mif_num(expr: &str, radix: u32, idx: usize) -> u32 {
+    // negative decimal data is stored in two's complement
+    let parsed = i64::from_str_radix(expr, radix)
+        .unwrap_or_else(|_| panic!("Error parsing number {} on line {}", expr, idx + 1));
+    parsed as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::{assemble, image, Options}, isa::Isa, output::*, reader::*};
+
+    #[test]
+    fn read_written_mif() {
+        let image = Image {
+            words: vec![(0, 0x2005), (1, 0x68), (2, 0x69), (0x10, 0xA808)],
+            source: vec![(0, String::from("jal main")), (1, String::from(".asciiz hi"))],
+            symbols: vec![],
+        };
+        let written = String::from_utf8(Mif.write(&image, &WriteOptions::default())).unwrap();
+        assert_eq!(read_mif(&written), image);
+    }
+
+    #[test]
+    fn read_assembled_mif_with_percent_signs() {
+        let program = assemble("li $v0, 7\n.asciiz \"50%\"\nadd $v0, $v0, $v0\n", &Options::default());
+        let image = image(&program, &Isa::default());
+        let written = String::from_utf8(Mif.write(&image, &WriteOptions::default())).unwrap();
+        assert!(written.contains("-- .asciiz \"50%\""));
+        assert_eq!(read_mif(&written), image);
+    }
+
+    #[test]
+    fn read_mif_ranges_and_radixes() {
+        let text = "
+            % written by
+              another tool %
+            DEPTH = 32; WIDTH = 16;
+            ADDRESS_RADIX = DEC;
+            DATA_RADIX = BIN;
+            CONTENT BEGIN
+                [0..3] : 1 10;      -- alternating
+                8 : 1111000000000000;
+                [20..21] : 0;
+            END;
+        ";
+        assert_eq!(read_mif(text).words, vec![(0, 1), (1, 2), (2, 1), (3, 2), (8, 0xF000), (20, 0), (21, 0)]);
+        assert_eq!(read_mif(text).source, vec![(0, String::from("alternating"))]);
+    }
+
+    #[test]
+    fn read_mif_negative_decimal() {
+        let text = "WIDTH = 16;\nDATA_RADIX = DEC;\nCONTENT\nBEGIN\n0 : -1;\nEND;\n";
+        assert_eq!(read_mif(text).words, vec![(0, 0xFFFF)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Address 10 on line 3 is beyond the depth of 16 words")]
+    fn read_mif_checks_depth() {
+        read_mif("DEPTH = 16;\nCONTENT BEGIN\n[0..10] : 0;\nEND;\n");
+    }
+
+    #[test]
+    fn read_written_ihex() {
+        let image = Image::from_words(vec![(0, 0x2005), (0x7FFF, 0x1234), (0x8000, 0x5678)], &Default::default());
+        for order in [ByteOrder::Big, ByteOrder::Little].iter() {
+            let options = WriteOptions { byte_order: *order, ..WriteOptions::default() };
+            let written = String::from_utf8(IntelHex.write(&image, &options)).unwrap();
+            assert_eq!(read_image(&written, *order), image);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Bad checksum on line 1")]
+    fn read_ihex_checks_checksums() {
+        read_ihex(":0600000020050068006905\n", ByteOrder::Big);
+    }
+
+    #[test]
+    fn read_written_binary() {
+        let image = Image::from_words(vec![(0, 0x2005), (1, 0x0068), (2, 0), (3, 0xA808)], &Default::default());
+        for order in [ByteOrder::Big, ByteOrder::Little].iter() {
+            let options = WriteOptions { byte_order: *order, ..WriteOptions::default() };
+            assert_eq!(read_binary(&Binary.write(&image, &options), *order), image);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Binary image has an odd number of bytes (3)")]
+    fn read_binary_checks_length() {
+        read_binary(&[0x20, 0x05, 0x00], ByteOrder::Big);
+    }
+}
@@ -0,0 +1,395 @@
+/*
+    Instruction-level simulator for the 16-bit ISA, for running programs
+    without the FPGA. Memory holds 16K words and is word addressed like the
+    assembler, while registers hold byte addresses as in the principles of
+    operation: `jal` leaves the byte address of the next instruction in
+    $ra, `jmpi` jumps to a byte address, and `lw`/`sw` address the word at
+    byte address `$2 + imm`.
+
+    A program stops when it jumps or branches to itself, the usual way to
+    end one, or when the PC reaches a word the image did not load, which
+    is how programs without an ending loop run off their last instruction.
+
+    The ALU status lines are those of the last instruction that used the
+    ALU; j-type jumps leave them alone.
+
+    Devices attached at a byte address take the loads and stores of the
+    word there in place of memory.
+
+    Illegal instructions, loads and stores outside memory and failing
+    devices fault: the step returns why and leaves the PC on the
+    instruction, so a debugger can show where the program went wrong.
+*/
+
+use alu::alu;
+use alu::alu_function;
+use alu::Flags;
+use devices::Device;
+use isa::Format;
+use isa::Isa;
+use memory::MEMORY_WORDS;
+use output::Image;
+
+pub struct Machine {
+    pub registers: Vec<u16>, // as many as the register fields can number
+    pub pc: u16, // word address of the next instruction
+    pub memory: Vec<u16>,
+    pub loaded: Vec<bool>, // words the image gave a value
+    pub steps: u64,
+    pub halted: bool,
+    pub flags: Flags,
+    devices: Vec<(u16, Box<dyn Device>)>,
+    isa: Isa,
+    zero: usize,
+    ra: usize,
+}
+
+impl Machine {
+    pub fn new(isa: &Isa) -> Machine {
+        Machine {
+            registers: vec![0; 1 << isa.layout(Format::R).field("r1").width()],
+            pc: 0,
+            memory: vec![0; MEMORY_WORDS as usize],
+            loaded: vec![false; MEMORY_WORDS as usize],
+            steps: 0,
+            halted: false,
+            flags: Flags::default(),
+            devices: Vec::new(),
+            isa: isa.clone(),
+            zero: isa.register_number("$0") as usize,
+            ra: isa.register_number("$ra") as usize,
+        }
+    }
+
+    /*
+        Loads the image's words and starts at its `main` symbol, or at
+        address 0 if it has none.
+    */
+    pub fn load(&mut self, image: &Image) {
+        for (addr, word) in image.words.iter() {
+            if *addr as u32 >= MEMORY_WORDS {
+                panic!("Image word at {:#X} is outside the {:#X} words of memory", addr, MEMORY_WORDS);
+            }
+            self.memory[*addr as usize] = *word;
+            self.loaded[*addr as usize] = true;
+        }
+        self.pc = image.symbols
+            .iter()
+            .find(|(name, _)| name == "main")
+            .map_or(0, |(_, addr)| *addr);
+    }
+
+    pub fn attach(&mut self, address: u16, device: Box<dyn Device>) {
+        if let Some((_, other)) = self.devices.iter().find(|(other, _)| other >> 1 == address >> 1) {
+            panic!("Cannot attach the {} device at {:#06X}, the {} device is already there",
+                device.name(), address, other.name());
+        }
+        self.devices.push((address, device));
+    }
+
+    /*
+        Runs until the program stops, panicking if it hasn't within
+        max_steps instructions. Fails with the first fault.
+    */
+    pub fn run(&mut self, max_steps: u64) -> Result<(), String> {
+        while !self.halted {
+            if self.steps >= max_steps {
+                panic!("Program did not stop within {} steps; PC is {:#06X}", max_steps, self.pc);
+            }
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /*
+        Executes the instruction at the PC, or returns the fault it causes
+        with the PC and flags left as they were before it.
+    */
+    pub fn step(&mut self) -> Result<(), String> {
+        let (pc, flags) = (self.pc, self.flags);
+        self.execute().inspect_err(|_| {
+            self.pc = pc;
+            self.flags = flags;
+        })
+    }
+
+    fn execute(&mut self) -> Result<(), String> {
+        if self.halted {
+            return Ok(());
+        }
+        let pc = self.pc;
+        if pc as usize >= self.memory.len() || !self.loaded[pc as usize] {
+            self.halted = true;
+            return Ok(());
+        }
+        let word = self.memory[pc as usize];
+        let encoding = match self.isa.decode(word).first() {
+            Some(encoding) => (*encoding).clone(),
+            None => return Err(format!("Illegal instruction {:04X} at {:#06X}", word, pc)),
+        };
+        let layout = self.isa.layout(encoding.format).clone();
+        let reg = |name: &str| layout.field(name).extract(word) as usize;
+        let imm = || {
+            let field = layout.field("imm");
+            let shift = 16 - field.width();
+            ((field.extract(word) << shift) as i16 >> shift) as u16
+        };
+        let next = pc.wrapping_add(1);
+        self.pc = next;
+        let mnemonic = encoding.mnemonic.as_str();
+        match encoding.format {
+            Format::R => {
+                let (a, b) = (self.registers[reg("r2")], self.registers[reg("r3")]);
+                let result = self.alu(mnemonic, a, b)?;
+                self.set_register(reg("r1"), result);
+            },
+            Format::J => {
+                let field = layout.field("address");
+                let region = !(field.mask() >> field.lo);
+                if mnemonic == "jal" {
+                    self.set_register(self.ra, next << 1);
+                }
+                self.pc = (next & region) | field.extract(word);
+            },
+            Format::I => {
+                let (r1, r2) = (reg("r1"), reg("r2"));
+                match mnemonic {
+                    "lw" => {
+                        let address = self.alu(mnemonic, self.registers[r2], imm())?;
+                        let value = self.load_word(address)?;
+                        self.set_register(r1, value);
+                    },
+                    "sw" => {
+                        let address = self.alu(mnemonic, self.registers[r2], imm())?;
+                        let value = self.registers[r1];
+                        self.store_word(address, value)?;
+                    },
+                    "beq" | "bne" => {
+                        self.alu(mnemonic, self.registers[r2], self.registers[r1])?;
+                        if self.flags.zero == (mnemonic == "beq") {
+                            self.pc = next.wrapping_add(imm());
+                        }
+                    },
+                    "addi" | "subi" => {
+                        let result = self.alu(mnemonic, self.registers[r2], imm())?;
+                        self.set_register(r1, result);
+                    },
+                    other => return Err(format!("The simulator cannot execute {} at {:#06X}", other, pc)),
+                }
+            },
+            Format::IWide => {
+                let r1 = reg("r1");
+                match mnemonic {
+                    "jmpi" | "jali" => {
+                        let target = self.alu(mnemonic, self.registers[r1], imm() << 1)?;
+                        if mnemonic == "jali" {
+                            self.set_register(self.ra, next << 1);
+                        }
+                        self.pc = target >> 1;
+                    },
+                    "lli" | "lui" => {
+                        let result = self.alu(mnemonic, self.registers[r1], imm())?;
+                        self.set_register(r1, result);
+                    },
+                    other => return Err(format!("The simulator cannot execute {} at {:#06X}", other, pc)),
+                }
+            },
+        }
+        self.steps += 1;
+        if self.pc == pc {
+            self.halted = true;
+        }
+        Ok(())
+    }
+
+    pub fn load_word(&mut self, address: u16) -> Result<u16, String> {
+        if let Some((_, device)) = self.devices.iter_mut().find(|(at, _)| at >> 1 == address >> 1) {
+            return device.read();
+        }
+        match self.memory.get((address >> 1) as usize) {
+            Some(word) => Ok(*word),
+            None => Err(format!("Load from {:#06X} at {:#06X} is outside memory", address, self.pc.wrapping_sub(1))),
+        }
+    }
+
+    pub fn store_word(&mut self, address: u16, value: u16) -> Result<(), String> {
+        let pc = self.pc.wrapping_sub(1);
+        if let Some((_, device)) = self.devices.iter_mut().find(|(at, _)| at >> 1 == address >> 1) {
+            return device.write(value);
+        }
+        match self.memory.get_mut((address >> 1) as usize) {
+            Some(word) => {
+                *word = value;
+                Ok(())
+            },
+            None => Err(format!("Store to {:#06X} at {:#06X} is outside memory", address, pc)),
+        }
+    }
+
+    /*
+        Runs the instruction's ALU function, latching its status lines.
+    */
+    fn alu(&mut self, mnemonic: &str, a: u16, b: u16) -> Result<u16, String> {
+        let function = alu_function(mnemonic).ok_or_else(|| format!("The ALU has no function for {}", mnemonic))?;
+        let output = alu(function, a, b);
+        self.flags = output.flags;
+        Ok(output.result)
+    }
+
+    pub fn isa(&self) -> &Isa {
+        &self.isa
+    }
+
+    /*
+        Writes a register, leaving $zero alone.
+    */
+    pub fn set_register(&mut self, register: usize, value: u16) {
+        if register != self.zero {
+            self.registers[register] = value;
+        }
+    }
+
+    /*
+        One line per register with its value in hex and signed decimal.
+    */
+    pub fn dump_registers(&self) -> String {
+        let mut text = String::new();
+        for (name, number) in self.isa.registers.iter() {
+            let value = self.registers[*number as usize];
+            text += &format!("{:<4}= {:#06X} {:>6}\n", name, value, value as i16);
+        }
+        text += &format!("pc  = {:#06X} after {} steps\n", self.pc, self.steps);
+        text += &format!("flags {}\n", self.flags);
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{assembler::assemble, assembler::image, assembler::Options, devices::*, isa::Isa, simulator::*};
+    use std::{cell::RefCell, io::Write, rc::Rc};
+
+    fn run(source: &str) -> Machine {
+        let isa = Isa::default();
+        let mut machine = Machine::new(&isa);
+        machine.load(&image(&assemble(source, &Options::default()), &isa));
+        machine.run(10000).unwrap();
+        machine
+    }
+
+    #[test]
+    fn arithmetic_and_shifts() {
+        let machine = run("
+            li $v0, 0x1234
+            lli $v1, -4
+            add $a0, $v0, $v1
+            sub $a1, $v1, $v0
+            nor $sp, $0, $0
+            lli $at, 4
+            sra $ra, $v1, $at
+            srl $v1, $v1, $at
+            addi $0, $v0, 1
+        ");
+        assert_eq!(machine.registers, [0, 4, 0x1234, 0x0FFF, 0x1230, 0xEDC8, 0xFFFF, 0xFFFF]);
+        assert_eq!(machine.steps, 10);
+    }
+
+    #[test]
+    fn loops_calls_and_memory() {
+        let machine = run("
+            .globl main
+            count:
+            .word 0
+            main:
+            lli $a0, 5
+            loop:
+            jal bump
+            addi $a0, $a0, -1
+            bne $a0, $0, loop
+            lli $v0, %lo(count)
+            lw $v0, 0($v0)
+            done:
+            beq $0, $0, done
+            bump:
+            lli $v1, %lo(count)
+            lw $a1, 0($v1)
+            addi $a1, $a1, 2
+            sw $a1, 0($v1)
+            jmpi 0($ra)
+        ");
+        assert_eq!(machine.registers[2], 10);
+        assert_eq!(machine.memory[0], 10);
+        assert_eq!(machine.pc, 7);
+    }
+
+    #[test]
+    fn jali_links_before_jumping() {
+        let machine = run("
+            lli $a0, %lo(target)
+            jali 0($a0)
+            .word 0xFFFF
+            target:
+            add $v0, $ra, $0
+        ");
+        assert_eq!(machine.registers[2], 4);
+        assert_eq!(machine.pc, 4);
+    }
+
+    #[test]
+    fn echo_through_devices() {
+        // copies input to output until the input device reads all ones
+        let isa = Isa::default();
+        let mut machine = Machine::new(&isa);
+        machine.load(&image(&assemble("
+            li $a0, 0xFF04
+            li $a1, 0xFF08
+            lli $at, -1
+            loop:
+            lw $v0, 0($a0)
+            beq $v0, $at, done
+            sw $v0, 0($a1)
+            beq $0, $0, loop
+            done:
+        ", &Options::default()), &isa));
+        let output = Rc::new(RefCell::new(Vec::new()));
+        machine.attach(INPUT_ADDRESS, Box::new(ConsoleInput::new(Box::new(&b"echo"[..]))));
+        machine.attach(OUTPUT_ADDRESS, Box::new(ConsoleOutput::new(Box::new(Shared(output.clone())))));
+        machine.run(1000).unwrap();
+        assert_eq!(&output.borrow()[..], b"echo");
+    }
+
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot attach the output device at 0xFF05, the input device is already there")]
+    fn devices_cannot_share_a_word() {
+        let mut machine = Machine::new(&Isa::default());
+        machine.attach(0xFF04, Box::new(ConsoleInput::new(Box::new(&b""[..]))));
+        machine.attach(0xFF05, Box::new(ConsoleOutput::new(Box::new(Vec::new()))));
+    }
+
+    #[test]
+    #[should_panic(expected = "Program did not stop within 10000 steps")]
+    fn step_limit() {
+        run("loop:\naddi $v0, $v0, 1\njmp loop\n");
+    }
+
+    #[test]
+    fn faults_leave_the_pc_on_the_instruction() {
+        let isa = Isa::default();
+        let mut machine = Machine::new(&isa);
+        machine.load(&image(&assemble("lli $v0, 1\n.word 0xFFFF\n", &Options::default()), &isa));
+        assert_eq!(machine.run(10), Err("Illegal instruction FFFF at 0x0001".to_string()));
+        assert_eq!((machine.pc, machine.steps, machine.registers[2]), (1, 1, 1));
+    }
+}
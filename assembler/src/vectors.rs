@@ -0,0 +1,193 @@
+/*
+    Golden test vectors for the ALU and control unit testbenches, from the
+    ALU model and the control specification the multicycle model runs.
+    Each set can be written as CSV or as a `$readmemh` file with one
+    vector per line, its fields packed into whole hex digits:
+
+        ALU        function[59:56] a[55:40] b[39:24] result[23:8] flags[7:0]
+        control    opcode[35:32] funct[31:28] state[27:24] zero[23:20]
+                   control_word[19:4] next_state[3:0]
+
+    Flags are packed as Z N Cout Cin V from bit 4 down, and control words
+    as described in multicycle.rs. Functions and states are numbered in
+    declaration order.
+*/
+
+use alu::alu;
+use alu::AluFunction;
+use alu::AluOutput;
+use alu::FUNCTIONS;
+use control::ControlSpec;
+use isa::Isa;
+use multicycle::ControlLines;
+use multicycle::State;
+
+const MAX_EXHAUSTIVE_BITS: u32 = 8;
+
+pub enum Operands {
+    Exhaustive(u32), // every pair of operands of this many bits
+    Random(u32, u64), // count per function and seed
+}
+
+pub struct AluVector {
+    pub function: AluFunction,
+    pub a: u16,
+    pub b: u16,
+    pub output: AluOutput,
+}
+
+pub struct ControlVector {
+    pub mnemonic: String,
+    pub opcode: u16,
+    pub funct: u16,
+    pub state: State,
+    pub zero: bool,
+    pub lines: ControlLines,
+    pub next: State,
+}
+
+pub fn alu_vectors(operands: &Operands) -> Vec<AluVector> {
+    let mut vectors = Vec::new();
+    for function in FUNCTIONS.iter().cloned() {
+        let pairs: Vec<(u16, u16)> = match operands {
+            Operands::Exhaustive(bits) => {
+                if *bits > MAX_EXHAUSTIVE_BITS {
+                    panic!("Exhaustive vectors cover operands of at most {} bits; use random operands for wider ones",
+                        MAX_EXHAUSTIVE_BITS);
+                }
+                let values = 0..1u32 << bits;
+                values.clone().flat_map(|a| values.clone().map(move |b| (a as u16, b as u16))).collect()
+            },
+            Operands::Random(count, seed) => {
+                let mut state = seed ^ 0x9E37_79B9_7F4A_7C15 ^ function as u64;
+                (0..*count).map(|_| {
+                    let bits = xorshift(&mut state);
+                    let b = bits as u16;
+                    // shift amounts past 16 are rare in random words
+                    let b = match function {
+                        AluFunction::SLL | AluFunction::SRL | AluFunction::SRA => b % 32,
+                        _ => b,
+                    };
+                    ((bits >> 16) as u16, b)
+                }).collect()
+            },
+        };
+        for (a, b) in pairs {
+            vectors.push(AluVector { function, a, b, output: alu(function, a, b) });
+        }
+    }
+    vectors
+}
+
+fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/*
+    The lines of every state of every instruction in the ISA, for both
+    values of the Zero status line. Fails if the ISA has an instruction
+    the control unit cannot execute.
+*/
+pub fn control_vectors(isa: &Isa, spec: &ControlSpec) -> Result<Vec<ControlVector>, String> {
+    let mut vectors = Vec::new();
+    for encoding in isa.instructions.iter() {
+        for state in spec.states(encoding) {
+            for zero in [false, true].iter() {
+                vectors.push(ControlVector {
+                    mnemonic: encoding.mnemonic.clone(),
+                    opcode: encoding.opcode,
+                    funct: encoding.funct,
+                    state,
+                    zero: *zero,
+                    lines: spec.lines(Some(encoding), state, *zero)?,
+                    next: spec.next_state(encoding, state),
+                });
+            }
+        }
+    }
+    Ok(vectors)
+}
+
+pub fn alu_csv(vectors: &[AluVector]) -> String {
+    let mut text = String::from("Function,A,B,Result,Zero,Negative,CarryOut,CarryIn,Overflow\n");
+    for vector in vectors.iter() {
+        let flags = &vector.output.flags;
+        text += &format!("{:?},{:04X},{:04X},{:04X},{},{},{},{},{}\n",
+            vector.function, vector.a, vector.b, vector.output.result,
+            flags.zero as u8, flags.negative as u8, flags.carry_out as u8, flags.carry_in as u8, flags.overflow as u8);
+    }
+    text
+}
+
+pub fn alu_readmemh(vectors: &[AluVector]) -> String {
+    let mut text = String::from("// function[59:56] a[55:40] b[39:24] result[23:8] flags[7:0] (Z N Cout Cin V)\n");
+    for vector in vectors.iter() {
+        text += &format!("{:X}{:04X}{:04X}{:04X}{:02X}\n",
+            vector.function as u8, vector.a, vector.b, vector.output.result, vector.output.flags.bits());
+    }
+    text
+}
+
+pub fn control_csv(vectors: &[ControlVector]) -> String {
+    let mut text = String::from("Instruction,Opcode,Funct,State,Zero,BranchOrJumpOrSequential,MemAddrFromPCOrALU,MemReadOrWrite,LoadIR,RegReadOrWrite,SecondOpComingFromImmediateOrReg2,WriteRegFromMemoryOrALU,ALUFunction,NextState,ControlWord\n");
+    for vector in vectors.iter() {
+        let lines = &vector.lines;
+        text += &format!("{},{:X},{:X},{:?},{},{:?},{:?},{:?},{},{:?},{:?},{:?},{:?},{:?},{:04X}\n",
+            vector.mnemonic, vector.opcode, vector.funct, vector.state, vector.zero as u8,
+            lines.branch_or_jump_or_sequential, lines.mem_addr_from_pc_or_alu, lines.mem_read_or_write,
+            lines.load_ir as u8, lines.reg_read_or_write, lines.second_op_from_immediate_or_reg2,
+            lines.write_reg_from_memory_or_alu, lines.alu_function, vector.next, lines.word());
+    }
+    text
+}
+
+pub fn control_readmemh(vectors: &[ControlVector]) -> String {
+    let mut text = String::from("// opcode[35:32] funct[31:28] state[27:24] zero[23:20] control_word[19:4] next_state[3:0]\n");
+    for vector in vectors.iter() {
+        text += &format!("{:X}{:X}{:X}{:X}{:04X}{:X}\n",
+            vector.opcode, vector.funct, vector.state as u8, vector.zero as u8, vector.lines.word(), vector.next as u8);
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{isa::Isa, vectors::*};
+
+    #[test]
+    fn exhaustive_alu_vectors() {
+        let vectors = alu_vectors(&Operands::Exhaustive(2));
+        assert_eq!(vectors.len(), FUNCTIONS.len() * 16);
+        let csv = alu_csv(&vectors);
+        assert_eq!(csv.lines().nth(2).unwrap(), "Add,0000,0001,0001,0,0,0,0,0");
+        assert_eq!(csv.lines().nth(17).unwrap(), "Sub,0000,0000,0000,1,0,1,1,0");
+        assert_eq!(alu_readmemh(&vectors).lines().nth(18).unwrap(), "100000001FFFF0A");
+    }
+
+    #[test]
+    fn random_alu_vectors_repeat_with_the_seed() {
+        let text = |seed| alu_readmemh(&alu_vectors(&Operands::Random(50, seed)));
+        assert_eq!(text(7), text(7));
+        assert_ne!(text(7), text(8));
+        assert_eq!(text(7).lines().count(), 1 + 50 * FUNCTIONS.len());
+    }
+
+    #[test]
+    fn control_truth_table() {
+        let vectors = control_vectors(&Isa::default(), &ControlSpec::default()).unwrap();
+        let csv = control_csv(&vectors);
+        assert!(csv.contains("\nbeq,5,0,Execute,1,Branch,PC,Idle,0,Read,Reg2,ALU,Sub,Fetch,8144\n"));
+        assert!(csv.contains("\nbeq,5,0,Execute,0,Hold,PC,Idle,0,Read,Reg2,ALU,Sub,Fetch,0144\n"));
+        assert!(csv.contains("\nlw,3,0,Memory,0,Hold,ALU,Read,0,Read,Reg2,ALU,Add,Writeback,2940\n"));
+        assert!(control_readmemh(&vectors).contains("\n9020C2800\n"));
+    }
+
+    #[test]
+    fn control_vectors_need_an_alu_function() {
+        let isa = Isa::load("[rtype]\nadd = 0\nnew xor = 5\n");
+        assert_eq!(control_vectors(&isa, &ControlSpec::default()).err(), Some(String::from("The ALU has no function for xor")));
+    }
+}